@@ -0,0 +1,68 @@
+use log::{Level, LevelFilter};
+use std::time::Instant;
+use tide::{utils::async_trait, Middleware, Next, Request};
+
+/// Logs every request with its status and duration, at a level that can be
+/// lowered (or raised) per path prefix, so frequently polled routes don't
+/// drown out the rest. Client and server errors are always logged at least
+/// as warnings and errors.
+#[derive(Debug, Default)]
+pub struct AccessLog {
+    /// Prefix of the app's routes, left out when matching `routes`.
+    pub base_path: String,
+    /// Level overrides by path prefix; the longest matching prefix wins.
+    pub routes: Vec<(String, LevelFilter)>,
+}
+
+impl AccessLog {
+    fn level(&self, path: &str) -> LevelFilter {
+        let path = path.strip_prefix(&self.base_path).unwrap_or(path);
+        self.routes
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(LevelFilter::Info, |&(_, level)| level)
+    }
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AccessLog {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let method = req.method();
+        let path = req.url().path().to_string();
+        let start = Instant::now();
+        let res = next.run(req).await;
+
+        let status = res.status();
+        let level = self.level(&path).to_level();
+        let level = if status.is_server_error() {
+            Some(Level::Error)
+        } else if status.is_client_error() {
+            Some(level.map_or(Level::Warn, |level| level.min(Level::Warn)))
+        } else {
+            level
+        };
+        if let Some(level) = level {
+            match res.error() {
+                Some(e) => log::log!(
+                    level,
+                    "{} {} {} in {:?}: {}",
+                    method,
+                    path,
+                    status as u16,
+                    start.elapsed(),
+                    e
+                ),
+                None => log::log!(
+                    level,
+                    "{} {} {} in {:?}",
+                    method,
+                    path,
+                    status as u16,
+                    start.elapsed()
+                ),
+            }
+        }
+        Ok(res)
+    }
+}
@@ -0,0 +1,109 @@
+use serde::Serialize;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+    Mutex,
+};
+use tide::StatusCode;
+
+/// Checks the `Authorization: Bearer <key>` header of an admin request.
+///
+/// Admin routes don't exist without `--admin-key`, so that case is a 404.
+pub fn authorize<State>(req: &tide::Request<State>, key: Option<&str>) -> tide::Result<()> {
+    let key = match key {
+        Some(key) => key,
+        None => return Err(tide::Error::from_str(StatusCode::NotFound, "not found")),
+    };
+    let given = req
+        .header("Authorization")
+        .and_then(|values| values.as_str().strip_prefix("Bearer "))
+        .map(str::trim);
+    match given {
+        Some(given) if constant_time_eq(given.as_bytes(), key.as_bytes()) => Ok(()),
+        _ => Err(tide::Error::from_str(
+            StatusCode::Unauthorized,
+            "missing or invalid admin key",
+        )),
+    }
+}
+
+/// Compares without bailing out at the first difference, so response times
+/// don't leak how much of the key was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Progress of the background re-encode started by `/admin/reencode`.
+#[derive(Debug, Default)]
+pub struct ReencodeProgress {
+    running: AtomicBool,
+    quality: AtomicU8,
+    done: AtomicU64,
+    kept: AtomicU64,
+    failed: AtomicU64,
+    total: AtomicU64,
+    /// When the last run finished, as a human-readable summary.
+    last_result: Mutex<Option<String>>,
+}
+
+/// What happened to one image of a re-encode.
+#[derive(Debug, Clone, Copy)]
+pub enum ReencodeOutcome {
+    Replaced,
+    Kept,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReencodeStatus {
+    pub running: bool,
+    pub quality: u8,
+    pub done: u64,
+    /// Images left as they were, because re-encoding didn't make them
+    /// smaller.
+    pub kept: u64,
+    pub failed: u64,
+    pub total: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_result: Option<String>,
+}
+
+impl ReencodeProgress {
+    /// Marks a run as started, unless one already is.
+    pub fn start(&self, quality: u8, total: u64) -> bool {
+        if self.running.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        self.quality.store(quality, Ordering::Relaxed);
+        self.done.store(0, Ordering::Relaxed);
+        self.kept.store(0, Ordering::Relaxed);
+        self.failed.store(0, Ordering::Relaxed);
+        self.total.store(total, Ordering::Relaxed);
+        true
+    }
+
+    pub fn record(&self, outcome: ReencodeOutcome) {
+        let counter = match outcome {
+            ReencodeOutcome::Replaced => &self.done,
+            ReencodeOutcome::Kept => &self.kept,
+            ReencodeOutcome::Failed => &self.failed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn finish(&self, summary: String) {
+        *self.last_result.lock().unwrap() = Some(summary);
+        self.running.store(false, Ordering::Release);
+    }
+
+    pub fn status(&self) -> ReencodeStatus {
+        ReencodeStatus {
+            running: self.running.load(Ordering::Acquire),
+            quality: self.quality.load(Ordering::Relaxed),
+            done: self.done.load(Ordering::Relaxed),
+            kept: self.kept.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+            last_result: self.last_result.lock().unwrap().clone(),
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use image::{codecs::png::PngDecoder, ImageFormat};
+use std::io::Cursor;
+
+/// Whether an upload holds more than a still image: a multi-frame GIF, an
+/// APNG, or an animated WebP. Only headers and frame boundaries are read.
+pub fn is_animated(bytes: &[u8], format: Option<ImageFormat>) -> bool {
+    match format {
+        Some(ImageFormat::Gif) => gif_frames(bytes) > 1,
+        Some(ImageFormat::Png) => PngDecoder::new(Cursor::new(bytes))
+            .map(|png| png.is_apng())
+            .unwrap_or(false),
+        Some(ImageFormat::WebP) => webp_is_animated(bytes),
+        _ => false,
+    }
+}
+
+/// Counts GIF frames, stopping at two.
+fn gif_frames(bytes: &[u8]) -> usize {
+    let mut decoder = match gif::DecodeOptions::new().read_info(Cursor::new(bytes)) {
+        Ok(decoder) => decoder,
+        Err(_) => return 0,
+    };
+    let mut frames = 0;
+    while frames < 2 {
+        match decoder.next_frame_info() {
+            Ok(Some(_)) => frames += 1,
+            _ => break,
+        }
+    }
+    frames
+}
+
+/// Checks the animation flag of an extended (`VP8X`) WebP header.
+fn webp_is_animated(bytes: &[u8]) -> bool {
+    const ANIMATION_FLAG: u8 = 0x02;
+    bytes.len() > 20
+        && &bytes[0..4] == b"RIFF"
+        && &bytes[8..12] == b"WEBP"
+        && &bytes[12..16] == b"VP8X"
+        && bytes[20] & ANIMATION_FLAG != 0
+}
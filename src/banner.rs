@@ -0,0 +1,148 @@
+use image::{Rgb, RgbImage};
+
+const WIDTH: u32 = 640;
+const PADDING: u32 = 16;
+/// Each font pixel is drawn as a `SCALE`x`SCALE` square.
+const SCALE: u32 = 2;
+const GLYPH_W: u32 = 5;
+const GLYPH_H: u32 = 7;
+const ADVANCE: u32 = (GLYPH_W + 1) * SCALE;
+const LINE_HEIGHT: u32 = (GLYPH_H + 3) * SCALE;
+const MAX_LINES: usize = 12;
+
+/// Background of error banners.
+pub const ERROR: Rgb<u8> = Rgb([200, 16, 16]);
+/// Background of the placeholder served while an image is being crushed.
+pub const PENDING: Rgb<u8> = Rgb([96, 96, 96]);
+
+const TEXT: Rgb<u8> = Rgb([255, 255, 255]);
+
+/// Renders `message` as white text on a `background` banner, for clients that
+/// can only display an image.
+pub fn render(message: &str, background: Rgb<u8>) -> RgbImage {
+    let per_line = ((WIDTH - 2 * PADDING) / ADVANCE) as usize;
+    let text: String = message
+        .chars()
+        .map(|c| {
+            if c.is_ascii() {
+                c.to_ascii_uppercase()
+            } else {
+                '?'
+            }
+        })
+        .collect();
+    let lines = wrap(&text, per_line);
+    let height = 2 * PADDING + lines.len() as u32 * LINE_HEIGHT;
+
+    let mut img = RgbImage::from_pixel(WIDTH, height, background);
+    for (row, line) in lines.iter().enumerate() {
+        let y = PADDING + row as u32 * LINE_HEIGHT;
+        for (col, c) in line.chars().enumerate() {
+            let x = PADDING + col as u32 * ADVANCE;
+            draw_glyph(&mut img, glyph(c), x, y);
+        }
+    }
+    img
+}
+
+/// Greedy word wrap of ASCII `text`, hard-splitting words longer than a line.
+fn wrap(text: &str, per_line: usize) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        let mut word = word;
+        while word.len() > per_line {
+            if !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+            let (head, tail) = word.split_at(per_line);
+            lines.push(head.to_string());
+            word = tail;
+        }
+        if !line.is_empty() && line.len() + 1 + word.len() > per_line {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines.truncate(MAX_LINES);
+    lines
+}
+
+fn draw_glyph(img: &mut RgbImage, rows: [u8; 7], x: u32, y: u32) {
+    for (dy, bits) in rows.iter().enumerate() {
+        for dx in 0..GLYPH_W {
+            if bits & (1 << (GLYPH_W - 1 - dx)) == 0 {
+                continue;
+            }
+            for sy in 0..SCALE {
+                for sx in 0..SCALE {
+                    img.put_pixel(x + dx * SCALE + sx, y + dy as u32 * SCALE + sy, TEXT);
+                }
+            }
+        }
+    }
+}
+
+/// 5x7 bitmap for `c`, one byte per row, most significant of the low five
+/// bits on the left. Characters without a glyph are drawn as `?`.
+fn glyph(c: char) -> [u8; 7] {
+    match c {
+        'A' => [0x0e, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11],
+        'B' => [0x1e, 0x11, 0x11, 0x1e, 0x11, 0x11, 0x1e],
+        'C' => [0x0e, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0e],
+        'D' => [0x1e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x1e],
+        'E' => [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x1f],
+        'F' => [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x10],
+        'G' => [0x0e, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0f],
+        'H' => [0x11, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11],
+        'I' => [0x0e, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0c],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1f],
+        'M' => [0x11, 0x1b, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e],
+        'P' => [0x1e, 0x11, 0x11, 0x1e, 0x10, 0x10, 0x10],
+        'Q' => [0x0e, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0d],
+        'R' => [0x1e, 0x11, 0x11, 0x1e, 0x14, 0x12, 0x11],
+        'S' => [0x0f, 0x10, 0x10, 0x0e, 0x01, 0x01, 0x1e],
+        'T' => [0x1f, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0a, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0a],
+        'X' => [0x11, 0x11, 0x0a, 0x04, 0x0a, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x0a, 0x04, 0x04, 0x04, 0x04],
+        'Z' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1f],
+        '0' => [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+        '1' => [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        '2' => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+        '3' => [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+        '4' => [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+        '5' => [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+        '6' => [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+        '7' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+        '9' => [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+        ' ' => [0x00; 7],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0c, 0x04, 0x08],
+        ':' => [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x0c, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00],
+        '+' => [0x00, 0x04, 0x04, 0x1f, 0x04, 0x04, 0x00],
+        '=' => [0x00, 0x00, 0x1f, 0x00, 0x1f, 0x00, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1f],
+        '\'' => [0x0c, 0x04, 0x08, 0x00, 0x00, 0x00, 0x00],
+        '"' => [0x0a, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '!' => [0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04],
+        _ => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
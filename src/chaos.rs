@@ -0,0 +1,57 @@
+use rand::Rng;
+use std::time::Duration;
+use tide::{http::Method, utils::async_trait, Middleware, Next, Request, Response, StatusCode};
+
+/// Failure injection, for exercising clients' error handling against a real
+/// server. Never enabled unless asked for with `--chaos`.
+///
+/// A `percent` share of uploads fail with a `500` or `503` before reaching
+/// their handler, and the same share of image fetches are held back for up
+/// to `max_delay`.
+#[derive(Debug)]
+pub struct Chaos {
+    pub percent: u8,
+    pub max_delay: Duration,
+    /// Path prefix of the app, for recognizing the routes to disrupt.
+    pub base_path: String,
+}
+
+impl Chaos {
+    fn roll(&self) -> bool {
+        rand::thread_rng().gen_range(0..100) < self.percent
+    }
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Chaos {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let path = req
+            .url()
+            .path()
+            .strip_prefix(&self.base_path)
+            .unwrap_or_default()
+            .to_string();
+
+        if req.method() == Method::Post && path.starts_with("/upload") && self.roll() {
+            let status = if rand::random() {
+                StatusCode::InternalServerError
+            } else {
+                StatusCode::ServiceUnavailable
+            };
+            log::warn!("CHAOS: failing {} {} with {}", req.method(), path, status);
+            return Ok(Response::new(status));
+        }
+
+        if req.method() == Method::Get
+            && path.starts_with("/images/")
+            && !self.max_delay.is_zero()
+            && self.roll()
+        {
+            let delay = rand::thread_rng().gen_range(Duration::ZERO..=self.max_delay);
+            log::warn!("CHAOS: delaying {} {} by {:?}", req.method(), path, delay);
+            async_std::task::sleep(delay).await;
+        }
+
+        Ok(next.run(req).await)
+    }
+}
@@ -0,0 +1,134 @@
+use crate::config::CmykJpeg;
+use image::{
+    error::{DecodingError, ImageFormatHint},
+    DynamicImage, ImageError, ImageFormat, RgbImage,
+};
+
+/// Brings any decoded image down to 8 bits per channel, which is all the
+/// crush and JPEG encoder handle. 16-bit and float images become 8-bit RGB.
+pub fn to_8bit(img: DynamicImage) -> DynamicImage {
+    match img {
+        DynamicImage::ImageLuma8(_)
+        | DynamicImage::ImageLumaA8(_)
+        | DynamicImage::ImageRgb8(_)
+        | DynamicImage::ImageRgba8(_) => img,
+        other => DynamicImage::ImageRgb8(other.to_rgb8()),
+    }
+}
+
+/// Whether `bytes` are a JPEG with four (CMYK or YCCK) components, going
+/// by its header alone.
+pub fn is_cmyk_jpeg(bytes: &[u8]) -> bool {
+    let mut decoder = jpeg_decoder::Decoder::new(bytes);
+    decoder.read_info().is_ok()
+        && decoder
+            .info()
+            .is_some_and(|info| info.pixel_format == jpeg_decoder::PixelFormat::CMYK32)
+}
+
+/// Decodes a CMYK JPEG to RGB, reading its channels the way `interpretation`
+/// says.
+///
+/// The image crate assumes the Adobe convention, where the stored values are
+/// inverted; files from tools that store plain ink amounts come out as a
+/// negative that way.
+pub fn decode_cmyk_jpeg(
+    bytes: &[u8],
+    interpretation: CmykJpeg,
+) -> Result<DynamicImage, ImageError> {
+    let decoding_error = |e: jpeg_decoder::Error| {
+        ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Exact(ImageFormat::Jpeg),
+            e,
+        ))
+    };
+    let mut decoder = jpeg_decoder::Decoder::new(bytes);
+    let pixels = decoder.decode().map_err(decoding_error)?;
+    let info = decoder.info().expect("info is available after decoding");
+
+    // jpeg-decoder undoes the Adobe inversion, so its output is ink amounts
+    // for Adobe files and inverted ink amounts for the others.
+    let rgb = pixels
+        .chunks_exact(4)
+        .flat_map(|cmyk| {
+            let ink = |v: u8| match interpretation {
+                CmykJpeg::Adobe => v as u32,
+                CmykJpeg::Plain => 255 - v as u32,
+            };
+            let k = ink(cmyk[3]);
+            [0, 1, 2].map(|i| ((255 - ink(cmyk[i])) * (255 - k) / 255) as u8)
+        })
+        .collect();
+    let img = RgbImage::from_raw(info.width as u32, info.height as u32, rgb)
+        .expect("decoder returned width * height pixels");
+    Ok(DynamicImage::ImageRgb8(img))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crush::{BitCrush, CrushOptions};
+    use image::{codecs::png::PngEncoder, ImageBuffer, ImageEncoder, Rgb};
+
+    /// Crushes `img` and encodes the result, like an upload would be.
+    fn crush_to_jpeg(img: DynamicImage) -> DynamicImage {
+        let options = CrushOptions {
+            deterministic: true,
+            ..Default::default()
+        };
+        let (crushed, _) = to_8bit(img).bitcrush(&options).unwrap();
+        let mut jpeg = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 25)
+            .encode_image(&crushed)
+            .unwrap();
+        image::load_from_memory_with_format(&jpeg, ImageFormat::Jpeg).unwrap()
+    }
+
+    #[test]
+    fn crushes_16_bit_png() {
+        let img: ImageBuffer<Rgb<u16>, Vec<u16>> = ImageBuffer::from_fn(32, 24, |x, y| {
+            Rgb([x as u16 * 2000, y as u16 * 2500, 40000])
+        });
+        let bytes: Vec<u8> = img.as_raw().iter().flat_map(|v| v.to_be_bytes()).collect();
+        let mut png = Vec::new();
+        PngEncoder::new(&mut png)
+            .write_image(&bytes, 32, 24, image::ColorType::Rgb16)
+            .unwrap();
+
+        let decoded = image::load_from_memory(&png).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::Rgb16);
+        let output = crush_to_jpeg(decoded);
+        assert_eq!((output.width(), output.height()), (32, 24));
+    }
+
+    #[test]
+    fn crushes_cmyk_jpeg() {
+        // Pure cyan ink. jpeg-encoder writes Adobe-style inverted CMYK.
+        let cmyk: Vec<u8> = [255, 0, 0, 0].repeat(16 * 16);
+        let mut jpeg = Vec::new();
+        jpeg_encoder::Encoder::new(&mut jpeg, 100)
+            .encode(&cmyk, 16, 16, jpeg_encoder::ColorType::Cmyk)
+            .unwrap();
+        assert!(is_cmyk_jpeg(&jpeg));
+
+        let decoded = decode_cmyk_jpeg(&jpeg, CmykJpeg::Adobe).unwrap();
+        let [r, g, b] = decoded.to_rgb8().get_pixel(8, 8).0;
+        assert!(
+            r < 40 && g > 215 && b > 215,
+            "expected cyan, got {:?}",
+            [r, g, b]
+        );
+
+        // Read as plain, the empty K channel becomes full black ink.
+        let plain = decode_cmyk_jpeg(&jpeg, CmykJpeg::Plain).unwrap();
+        let [r, g, b] = plain.to_rgb8().get_pixel(8, 8).0;
+        assert!(
+            r < 40 && g < 40 && b < 40,
+            "expected black, got {:?}",
+            [r, g, b]
+        );
+
+        let output = crush_to_jpeg(decoded);
+        assert_eq!((output.width(), output.height()), (16, 16));
+    }
+}
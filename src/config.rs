@@ -0,0 +1,273 @@
+use clap::{ArgAction, Parser, ValueEnum};
+use image::Rgb;
+use log::LevelFilter;
+use std::path::PathBuf;
+
+/// Server configuration, read from the command line (or the environment).
+#[derive(Debug, Clone, Parser)]
+#[command(author, version, about)]
+pub struct Config {
+    /// Make the whole instance deterministic: the RNG is seeded with a fixed
+    /// value and every random choice (intermediate dimensions, intermediate
+    /// JPEG quality) is replaced by the midpoint of its range, so the same
+    /// input always yields the same output.
+    #[arg(long, env = "MORE_JPEG_DETERMINISTIC")]
+    pub deterministic: bool,
+
+    /// Send security headers (`X-Content-Type-Options` everywhere, plus a
+    /// Content-Security-Policy and referrer/framing policies on HTML pages).
+    #[arg(long, env = "MORE_JPEG_SECURITY_HEADERS", default_value_t = true, action = ArgAction::Set)]
+    pub security_headers: bool,
+
+    /// Also store a thumbnail of every image, at most this many pixels on its
+    /// longest side, served at `/images/:name/thumb`. Disabled when unset.
+    #[arg(long, env = "MORE_JPEG_THUMBNAIL_SIZE", value_parser = clap::value_parser!(u32).range(1..))]
+    pub thumbnail_size: Option<u32>,
+
+    /// Also store a tiny blurred preview of every image (a few hundred bytes),
+    /// returned as a data URI by `/images/:name/meta`.
+    #[arg(long, env = "MORE_JPEG_LQIP")]
+    pub lqip: bool,
+
+    /// Sizes, in pixels on the longest side, that `/upload?size=` may resize
+    /// crushed images to, comma-separated. Without it, images keep their
+    /// dimensions and `size` is refused.
+    #[arg(long, env = "MORE_JPEG_OUTPUT_SIZES", value_delimiter = ',', value_parser = clap::value_parser!(u32).range(1..=crate::transform::MAX_RESIZE_DIMENSION as i64))]
+    pub output_sizes: Vec<u32>,
+
+    /// Largest number of pixels (width * height) an uploaded image may have.
+    #[arg(long, env = "MORE_JPEG_MAX_PIXELS", default_value_t = 40_000_000)]
+    pub max_pixels: u64,
+
+    /// Largest body, in bytes, `/upload/raw` accepts. Uncompressed pixels
+    /// need a lot more room than an encoded image, but are still bounded.
+    #[arg(long, env = "MORE_JPEG_MAX_RAW_UPLOAD_BYTES", default_value_t = 256 * 1024 * 1024)]
+    pub max_raw_upload_bytes: u64,
+
+    /// Most crush passes a single request may cost in total, counting every
+    /// parameter that multiplies the work (passes, and anything that crushes
+    /// the image several times over). Checked before any crushing starts.
+    #[arg(long, env = "MORE_JPEG_MAX_TOTAL_PASSES", default_value_t = 64, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_total_passes: u64,
+
+    /// When an upload can't be decoded or crushed, answer `200 OK` with an
+    /// image of the error message instead of an HTTP error, for clients that
+    /// can only show images.
+    #[arg(long, env = "MORE_JPEG_ERROR_IMAGES")]
+    pub error_images: bool,
+
+    /// Seconds a client gets to send an upload's body before the request is
+    /// cut off with `408 Request Timeout`.
+    #[arg(long, env = "MORE_JPEG_BODY_READ_TIMEOUT", default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    pub body_read_timeout: u64,
+
+    /// JPEG served, with a `Retry-After` header, for images that are still
+    /// being crushed. A generated "processing" banner is used when unset.
+    #[arg(long, env = "MORE_JPEG_PROCESSING_PLACEHOLDER")]
+    pub processing_placeholder: Option<PathBuf>,
+
+    /// Answer `/upload` as soon as the body is read, with a pending `src`,
+    /// and crush in the background. Until it's done, the image is served as
+    /// the processing placeholder.
+    #[arg(long, env = "MORE_JPEG_DEFERRED_CRUSH")]
+    pub deferred_crush: bool,
+
+    /// Largest crushed image, in bytes, that `/upload?encoding=datauri`
+    /// inlines in its response. Bigger ones only get a `src`.
+    #[arg(long, env = "MORE_JPEG_MAX_DATA_URI_SIZE", default_value_t = 256 * 1024)]
+    pub max_data_uri_size: usize,
+
+    /// MIME types `/images/:name` may serve, comma-separated. Stored images
+    /// of any other type are refused with `415 Unsupported Media Type`, in
+    /// case something other than a crush put them in the store.
+    #[arg(
+        long,
+        env = "MORE_JPEG_SERVE_MIME_TYPES",
+        value_delimiter = ',',
+        default_value = "image/jpeg,image/png,image/gif,image/webp,image/avif",
+        value_parser = parse_mime_type
+    )]
+    pub serve_mime_types: Vec<String>,
+
+    /// Key for the `/admin` endpoints, sent as `Authorization: Bearer <key>`.
+    /// Those endpoints don't exist when unset.
+    #[arg(long, env = "MORE_JPEG_ADMIN_KEY", hide_env_values = true)]
+    pub admin_key: Option<String>,
+
+    /// Log a summary of `/stats` every this many seconds. Off when unset.
+    #[arg(long, env = "MORE_JPEG_STATS_LOG_INTERVAL", value_parser = clap::value_parser!(u64).range(1..))]
+    pub stats_log_interval: Option<u64>,
+
+    /// Most memory, in bytes, all crushes in flight may use for their
+    /// buffers together. Crushes that would go over it are answered with
+    /// `503 Service Unavailable` (or wait, when deferred). Unlimited when unset.
+    #[arg(long, env = "MORE_JPEG_MAX_CRUSH_MEMORY")]
+    pub max_crush_memory: Option<u64>,
+
+    /// Largest total size, in bytes, of an upload sent in chunks through
+    /// `/upload/init`.
+    #[arg(long, env = "MORE_JPEG_MAX_RESUMABLE_SIZE", default_value_t = 64 * 1024 * 1024)]
+    pub max_resumable_size: u64,
+
+    /// Seconds a chunked upload may go without a new chunk before it is
+    /// dropped.
+    #[arg(long, env = "MORE_JPEG_RESUMABLE_TTL", default_value_t = 600, value_parser = clap::value_parser!(u64).range(1..))]
+    pub resumable_ttl: u64,
+
+    /// Most upload bytes a single client (by IP address) may send per
+    /// `--upload-byte-window`, counting every route that takes an image
+    /// body. Over it, uploads get `429 Too Many Requests`. Unlimited when
+    /// unset.
+    #[arg(long, env = "MORE_JPEG_UPLOAD_BYTE_RATE", value_parser = clap::value_parser!(u64).range(1..))]
+    pub upload_byte_rate: Option<u64>,
+
+    /// Seconds over which `--upload-byte-rate` is refilled.
+    #[arg(long, env = "MORE_JPEG_UPLOAD_BYTE_WINDOW", default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    pub upload_byte_window: u64,
+
+    /// Log level of requests under a path prefix, as `PREFIX=LEVEL`
+    /// (`off`, `error`, `warn`, `info`, `debug` or `trace`), e.g.
+    /// `/stats=trace`. Comma-separated or repeated; the longest matching
+    /// prefix wins, and other requests are logged at `info`. Errors are
+    /// always logged as warnings or errors.
+    #[arg(long, env = "MORE_JPEG_LOG_ROUTE", value_delimiter = ',', value_parser = parse_route_level)]
+    pub log_route: Vec<(String, LevelFilter)>,
+
+    /// Record how each image was crushed (passes, seed, quality) in a JPEG
+    /// comment, so a downloaded file carries its own recipe.
+    #[arg(long, env = "MORE_JPEG_CRUSH_COMMENT")]
+    pub crush_comment: bool,
+
+    /// Let `/upload?debug=true` return a breakdown of the crush: timings,
+    /// the random choices of every pass and the final size. Meant for tuning,
+    /// not for public servers.
+    #[arg(long, env = "MORE_JPEG_CRUSH_DEBUG")]
+    pub crush_debug: bool,
+
+    /// Testing only: make this percentage of uploads fail with a `500` or
+    /// `503`, and delay the same share of image fetches. Off by default.
+    #[arg(long, env = "MORE_JPEG_CHAOS", hide = true, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub chaos: Option<u8>,
+
+    /// Testing only: longest delay, in milliseconds, `--chaos` adds to an
+    /// image fetch.
+    #[arg(
+        long,
+        env = "MORE_JPEG_CHAOS_DELAY",
+        hide = true,
+        default_value_t = 2000
+    )]
+    pub chaos_delay: u64,
+
+    /// Make the upload page an installable web app: serve a
+    /// `/manifest.json` and generated icons under `/icons`.
+    #[arg(long, env = "MORE_JPEG_PWA")]
+    pub pwa: bool,
+
+    /// Name of the installed web app, with `--pwa`.
+    #[arg(long, env = "MORE_JPEG_APP_NAME", default_value = "More JPEG")]
+    pub app_name: String,
+
+    /// Theme color of the installed web app and its icons, with `--pwa`.
+    #[arg(long, env = "MORE_JPEG_THEME_COLOR", default_value = "#c0392b", value_parser = parse_theme_color)]
+    pub theme_color: Rgb<u8>,
+
+    /// Path prefix the app is mounted under behind a reverse proxy, e.g.
+    /// `/crush`. Every route and generated `src` starts with it.
+    #[arg(long, env = "MORE_JPEG_BASE_PATH", default_value = "", value_parser = parse_base_path)]
+    pub base_path: String,
+
+    /// How to treat animated uploads. Crushing only ever looks at the first
+    /// frame, so rejecting them just saves decoding work and surprises.
+    #[arg(long, env = "MORE_JPEG_ANIMATED_INPUTS", value_enum, default_value_t = AnimatedInputs::Flatten)]
+    pub animated_inputs: AnimatedInputs,
+
+    /// How the channels of CMYK JPEGs are read. Photoshop and most other
+    /// tools store them inverted (Adobe); a few store plain ink amounts.
+    #[arg(long, env = "MORE_JPEG_CMYK_JPEG", value_enum, default_value_t = CmykJpeg::Adobe)]
+    pub cmyk_jpeg: CmykJpeg,
+
+    /// What to do with uploads over `--max-pixels`. Downscaling still has to
+    /// decode the full image, so it only accepts images up to
+    /// `OVERSIZE_DECODE_FACTOR` times the limit.
+    #[arg(long, env = "MORE_JPEG_OVERSIZE_POLICY", value_enum, default_value_t = OversizePolicy::Reject)]
+    pub oversize_policy: OversizePolicy,
+}
+
+/// How the channels of a CMYK JPEG are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CmykJpeg {
+    /// Inverted, as written by Adobe software.
+    Adobe,
+    /// Plain ink amounts.
+    Plain,
+}
+
+/// What to do with uploads over the pixel limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OversizePolicy {
+    /// Refuse them with `413 Payload Too Large`.
+    Reject,
+    /// Shrink them to fit the limit before crushing.
+    Downscale,
+}
+
+/// What to do with animated uploads (GIF, APNG, animated WebP).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AnimatedInputs {
+    /// Crush the first frame only.
+    Flatten,
+    /// Refuse them with `415 Unsupported Media Type`.
+    Reject,
+}
+
+fn parse_theme_color(color: &str) -> Result<Rgb<u8>, String> {
+    crate::transform::parse_color(color).map_err(|e| e.to_string())
+}
+
+/// Keeps only the essence of a MIME type (`type/subtype`, no parameters),
+/// lowercased.
+fn parse_mime_type(mime: &str) -> Result<String, String> {
+    let essence = mime.split(';').next().unwrap_or_default().trim();
+    match essence.split_once('/') {
+        Some((kind, subtype)) if !kind.is_empty() && !subtype.is_empty() => {
+            Ok(essence.to_ascii_lowercase())
+        }
+        _ => Err(format!("invalid MIME type {:?}", mime)),
+    }
+}
+
+fn parse_route_level(route: &str) -> Result<(String, LevelFilter), String> {
+    let (prefix, level) = route
+        .split_once('=')
+        .ok_or_else(|| format!("expected PREFIX=LEVEL, got {:?}", route))?;
+    if !prefix.starts_with('/') {
+        return Err(format!("route prefix {:?} must start with '/'", prefix));
+    }
+    let level = level
+        .parse()
+        .map_err(|_| format!("invalid log level {:?}", level))?;
+    Ok((prefix.to_string(), level))
+}
+
+/// Normalizes a base path to either `""` or `/segment(s)` without a
+/// trailing slash. It's rendered as is into the page's HTML and scripts, so
+/// segments may only use unreserved URL characters.
+fn parse_base_path(path: &str) -> Result<String, String> {
+    if path.is_empty() {
+        return Ok(String::new());
+    }
+    if !path.starts_with('/') {
+        return Err(format!("base path {:?} must start with '/'", path));
+    }
+    if let Some(c) = path
+        .chars()
+        .find(|&c| !(c.is_ascii_alphanumeric() || "/-._~".contains(c)))
+    {
+        return Err(format!(
+            "base path {:?} contains {:?}, expected only letters, digits and '/-._~'",
+            path, c
+        ));
+    }
+    Ok(path.trim_end_matches('/').to_string())
+}
@@ -0,0 +1,309 @@
+use crate::ycbcr::{self, YCbCrCrush};
+use image::{imageops::FilterType, DynamicImage, GenericImageView};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+/// Seed used for every crush when running in deterministic mode.
+pub const DETERMINISTIC_SEED: u64 = 0x6d6f_7265_6a70_6567;
+
+/// Range the intermediate JPEG quality is picked from on each pass.
+const INTERMEDIATE_QUALITY: Range<u8> = 10..30;
+
+/// Side of the pixel blocks JPEG compresses independently.
+const JPEG_BLOCK: u32 = 8;
+
+/// Strongest `block_emphasis` allowed.
+pub const MAX_BLOCK_EMPHASIS: f32 = 10.0;
+
+/// Number of passes a crush runs when nothing else is asked for.
+pub const DEFAULT_PASSES: u32 = 2;
+
+/// Most passes a crush with a time budget runs, however fast they are.
+pub const MAX_BUDGET_PASSES: u32 = 16;
+
+#[derive(Debug, Clone)]
+pub struct CrushOptions {
+    /// Use a fixed seed and the midpoint of every random range.
+    pub deterministic: bool,
+    /// Number of resize/recompress passes, or the upper bound on them when a
+    /// `budget` is set.
+    pub passes: u32,
+    /// Stop early once another pass would likely overrun this much time.
+    /// At least one pass always runs.
+    pub budget: Option<Duration>,
+    /// Before crushing, exaggerate the discontinuities at the 8x8 block
+    /// boundaries by this factor. Only meaningful for JPEG inputs, whose
+    /// blocks line up with that grid.
+    pub block_emphasis: Option<f32>,
+    /// After the passes, crush luma and chroma separately with these
+    /// settings.
+    pub ycbcr: Option<YCbCrCrush>,
+    /// Record what every pass did in the report's `trace`.
+    pub trace: bool,
+    /// Put this rectangle of the input back, untouched, over the crushed
+    /// image. Has to lie within the image.
+    pub focus: Option<Focus>,
+    /// Seed the RNG with this instead of a random or the fixed seed. Random
+    /// choices then come from it even when `deterministic`, so the same seed
+    /// still always gives the same output.
+    pub seed: Option<u64>,
+}
+
+/// A rectangle of an image, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Focus {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Focus {
+    pub fn fits(&self, width: u32, height: u32) -> bool {
+        self.width > 0
+            && self.height > 0
+            && self
+                .x
+                .checked_add(self.width)
+                .is_some_and(|right| right <= width)
+            && self
+                .y
+                .checked_add(self.height)
+                .is_some_and(|bottom| bottom <= height)
+    }
+}
+
+impl Default for CrushOptions {
+    fn default() -> Self {
+        Self {
+            deterministic: false,
+            passes: DEFAULT_PASSES,
+            budget: None,
+            block_emphasis: None,
+            ycbcr: None,
+            trace: false,
+            focus: None,
+            seed: None,
+        }
+    }
+}
+
+/// What a crush actually did.
+#[derive(Debug, Clone, Default)]
+pub struct CrushReport {
+    pub passes: u32,
+    /// Seed of the RNG behind every random choice of the crush.
+    pub seed: u64,
+    /// How long the crush took.
+    pub elapsed: Duration,
+    /// One entry per pass, with `CrushOptions::trace`.
+    pub trace: Vec<PassTrace>,
+}
+
+/// The random choices of one pass and what they cost.
+#[derive(Debug, Clone)]
+pub struct PassTrace {
+    /// Intermediate size the image was resized to.
+    pub width: u32,
+    pub height: u32,
+    /// Quality the intermediate JPEG was encoded at.
+    pub quality: u8,
+    /// Size of the intermediate JPEG.
+    pub bytes: usize,
+    pub elapsed: Duration,
+}
+
+impl CrushReport {
+    /// Human-readable summary of how an image was crushed, and the `size` it
+    /// was scaled to after, enough to crush the same input the same way
+    /// again.
+    pub fn recipe(&self, options: &CrushOptions, quality: u8, size: Option<u32>) -> String {
+        let mut recipe = format!(
+            "more-jpeg passes={} seed={} quality={}",
+            self.passes, self.seed, quality
+        );
+        if options.deterministic && options.seed.is_none() {
+            recipe.push_str(" deterministic=true");
+        }
+        if let Some(strength) = options.block_emphasis {
+            recipe.push_str(&format!(" block_emphasis={}", strength));
+        }
+        if let Some(YCbCrCrush { luma, chroma }) = options.ycbcr {
+            recipe.push_str(&format!(
+                " luma_scale={} luma_quality={} chroma_scale={} chroma_quality={}",
+                luma.scale, luma.quality, chroma.scale, chroma.quality
+            ));
+        }
+        if let Some(Focus {
+            x,
+            y,
+            width,
+            height,
+        }) = options.focus
+        {
+            recipe.push_str(&format!(" focus={},{},{},{}", x, y, width, height));
+        }
+        if let Some(size) = size {
+            recipe.push_str(&format!(" size={}", size));
+        }
+        recipe
+    }
+}
+
+impl CrushOptions {
+    fn seed(&self) -> u64 {
+        match self.seed {
+            Some(seed) => seed,
+            None if self.deterministic => DETERMINISTIC_SEED,
+            None => rand::random(),
+        }
+    }
+
+    /// Whether random choices are replaced by the midpoint of their range.
+    fn midpoints(&self) -> bool {
+        self.deterministic && self.seed.is_none()
+    }
+
+    fn pick_u32(&self, rng: &mut StdRng, range: Range<u32>) -> u32 {
+        if self.midpoints() {
+            range.start + (range.end - range.start) / 2
+        } else {
+            rng.gen_range(range)
+        }
+    }
+
+    fn pick_u8(&self, rng: &mut StdRng, range: Range<u8>) -> u8 {
+        if self.midpoints() {
+            range.start + (range.end - range.start) / 2
+        } else {
+            rng.gen_range(range)
+        }
+    }
+}
+
+/// Rough peak size, in bytes, of the buffers a crush of a `width`x`height`
+/// image holds at once: the image, an intermediate up to twice as wide and
+/// tall, its decoded copy, and the resize back, at up to 4 bytes per pixel.
+pub fn peak_memory(width: u32, height: u32) -> u64 {
+    width as u64 * height as u64 * 4 * (1 + 4 + 4 + 1)
+}
+
+pub trait BitCrush: Sized {
+    type Error;
+
+    fn bitcrush(self, options: &CrushOptions) -> Result<(Self, CrushReport), Self::Error>;
+}
+
+impl BitCrush for DynamicImage {
+    type Error = image::ImageError;
+
+    fn bitcrush(self, options: &CrushOptions) -> Result<(Self, CrushReport), Self::Error> {
+        let start = Instant::now();
+        let mut report = CrushReport::default();
+        let focus = options.focus.map(|focus| {
+            (
+                focus,
+                self.crop_imm(focus.x, focus.y, focus.width, focus.height),
+            )
+        });
+        let mut current = match options.block_emphasis {
+            Some(strength) => emphasize_blocks(self, strength),
+            None => self,
+        };
+        let (orig_w, orig_h) = current.dimensions();
+
+        report.seed = options.seed();
+        let mut rng = StdRng::seed_from_u64(report.seed);
+        let (temp_w, temp_h) = (
+            options.pick_u32(&mut rng, orig_w / 2..orig_w * 2),
+            options.pick_u32(&mut rng, orig_h / 2..orig_h * 2),
+        );
+
+        let mut out: Vec<u8> = Default::default();
+        while report.passes < options.passes {
+            let pass_start = Instant::now();
+            current = current
+                .resize_exact(temp_w, temp_h, FilterType::Nearest)
+                .rotate180()
+                .huerotate(180);
+            out.clear();
+            let quality = options.pick_u8(&mut rng, INTERMEDIATE_QUALITY);
+            {
+                let mut encoder =
+                    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut out, quality);
+                encoder.encode_image(&current)?;
+            }
+            current = image::load_from_memory_with_format(&out[..], image::ImageFormat::Jpeg)?
+                .resize_exact(orig_w, orig_h, FilterType::Nearest);
+            report.passes += 1;
+            if options.trace {
+                report.trace.push(PassTrace {
+                    width: temp_w,
+                    height: temp_h,
+                    quality,
+                    bytes: out.len(),
+                    elapsed: pass_start.elapsed(),
+                });
+            }
+
+            if let Some(budget) = options.budget {
+                // Assume the next pass costs as much as this one did.
+                if start.elapsed() + pass_start.elapsed() > budget {
+                    break;
+                }
+            }
+        }
+        // Every pass flips the image and its hues, so an odd number of passes
+        // needs one more flip to come out the right way up.
+        if report.passes % 2 == 1 {
+            current = current.rotate180().huerotate(180);
+        }
+        if let Some(settings) = options.ycbcr {
+            current = ycbcr::crush(&current, settings)?;
+        }
+        if let Some((focus, original)) = focus {
+            image::imageops::overlay(&mut current, &original, focus.x as i64, focus.y as i64);
+        }
+        report.elapsed = start.elapsed();
+        Ok((current, report))
+    }
+}
+
+/// Pushes the pixels on either side of every JPEG block boundary apart, so
+/// the blocking already present in a JPEG becomes harsh and obvious.
+fn emphasize_blocks(img: DynamicImage, strength: f32) -> DynamicImage {
+    let mut rgb = img.into_rgb8();
+    let (w, h) = rgb.dimensions();
+    let push = |a: u8, b: u8| {
+        let delta = (b as f32 - a as f32) * strength / 2.0;
+        (
+            (a as f32 - delta).clamp(0.0, 255.0) as u8,
+            (b as f32 + delta).clamp(0.0, 255.0) as u8,
+        )
+    };
+
+    for x in (JPEG_BLOCK..w).step_by(JPEG_BLOCK as usize) {
+        for y in 0..h {
+            let (mut left, mut right) = (*rgb.get_pixel(x - 1, y), *rgb.get_pixel(x, y));
+            for c in 0..3 {
+                (left[c], right[c]) = push(left[c], right[c]);
+            }
+            rgb.put_pixel(x - 1, y, left);
+            rgb.put_pixel(x, y, right);
+        }
+    }
+    for y in (JPEG_BLOCK..h).step_by(JPEG_BLOCK as usize) {
+        for x in 0..w {
+            let (mut top, mut bottom) = (*rgb.get_pixel(x, y - 1), *rgb.get_pixel(x, y));
+            for c in 0..3 {
+                (top[c], bottom[c]) = push(top[c], bottom[c]);
+            }
+            rgb.put_pixel(x, y - 1, top);
+            rgb.put_pixel(x, y, bottom);
+        }
+    }
+    DynamicImage::ImageRgb8(rgb)
+}
@@ -0,0 +1,36 @@
+use tide::http::{mime, Mime};
+
+/// Formats images are stored and served in.
+///
+/// This is the one place that knows how a format maps to file extensions
+/// and MIME types; everything else goes through it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Jpeg,
+}
+
+impl OutputFormat {
+    /// Extension used in the `src` of images of this format.
+    pub fn extension(self) -> &'static str {
+        self.extensions()[0]
+    }
+
+    /// Every extension accepted when serving an image of this format.
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            OutputFormat::Jpeg => &["jpg", "jpeg"],
+        }
+    }
+
+    pub fn matches_extension(self, extension: &str) -> bool {
+        self.extensions()
+            .iter()
+            .any(|ext| ext.eq_ignore_ascii_case(extension))
+    }
+
+    pub fn mime(self) -> Mime {
+        match self {
+            OutputFormat::Jpeg => mime::JPEG,
+        }
+    }
+}
@@ -0,0 +1,50 @@
+/// `ftyp` major brands used by HEIF/HEIC still images and sequences.
+const BRANDS: &[&[u8; 4]] = &[
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"mif1", b"msf1",
+];
+
+/// Whether `bytes` look like a HEIF/HEIC file, which the `image` crate can't
+/// decode on its own.
+pub fn is_heif(bytes: &[u8]) -> bool {
+    bytes.len() >= 12 && &bytes[4..8] == b"ftyp" && BRANDS.iter().any(|b| &bytes[8..12] == *b)
+}
+
+#[cfg(feature = "heif")]
+#[derive(Debug, thiserror::Error)]
+pub enum HeifDecodeError {
+    #[error("could not decode HEIF image: {0}")]
+    Heif(#[from] libheif_rs::HeifError),
+    #[error("HEIF decoder returned no interleaved RGB plane")]
+    MissingPlane,
+}
+
+/// Decodes the primary image of a HEIF file to 8-bit RGB. `check` is given
+/// the image dimensions before any pixels are decoded, and can veto them.
+#[cfg(feature = "heif")]
+pub fn decode(
+    bytes: &[u8],
+    check: impl FnOnce(u32, u32) -> tide::Result<()>,
+) -> tide::Result<image::DynamicImage> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let lib = LibHeif::new();
+    let ctx = HeifContext::read_from_bytes(bytes).map_err(HeifDecodeError::from)?;
+    let handle = ctx.primary_image_handle().map_err(HeifDecodeError::from)?;
+    check(handle.width(), handle.height())?;
+
+    let decoded = lib
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)
+        .map_err(HeifDecodeError::from)?;
+    let planes = decoded.planes();
+    let plane = planes.interleaved.ok_or(HeifDecodeError::MissingPlane)?;
+
+    // Rows may be padded, so copy them one by one.
+    let row_len = plane.width as usize * 3;
+    let mut pixels = Vec::with_capacity(row_len * plane.height as usize);
+    for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        pixels.extend_from_slice(&row[..row_len]);
+    }
+    let rgb = image::RgbImage::from_raw(plane.width, plane.height, pixels)
+        .ok_or(HeifDecodeError::MissingPlane)?;
+    Ok(image::DynamicImage::ImageRgb8(rgb))
+}
@@ -1,11 +1,54 @@
-use async_std::{fs::read_to_string, sync::RwLock};
-use image::{imageops::FilterType, DynamicImage, GenericImageView};
+use admin::ReencodeOutcome;
+use async_std::{
+    channel::{self, Receiver, Sender},
+    fs::read_to_string,
+    io::ReadExt,
+};
+use clap::Parser;
+use config::{AnimatedInputs, Config, OversizePolicy};
+use crush::{BitCrush, CrushOptions, CrushReport, Focus};
+use format::OutputFormat;
+use image::{io::Reader as ImageReader, DynamicImage, GrayImage, RgbImage, RgbaImage};
 use liquid::{Object, Template};
-use rand::Rng;
-use serde::Serialize;
-use std::{collections::HashMap, error::Error, sync::Arc};
-use tide::{http::Mime, Request, Response, StatusCode};
+use memory::{CrushMemory, Reservation};
+use ratelimit::ByteRateLimiter;
+use resumable::{AppendError, PartialUploads};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    io::Cursor,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use store::{Image, ImageStatus, ImageStore};
+use tide::{http::Mime, utils::After, Request, Response, StatusCode};
+use transform::Transforms;
 use ulid::Ulid;
+use ycbcr::{ComponentCrush, YCbCrCrush};
+
+mod access_log;
+mod admin;
+mod animation;
+mod banner;
+mod chaos;
+mod color;
+mod config;
+mod crush;
+mod format;
+mod heif;
+mod memory;
+mod palette;
+mod pwa;
+mod ratelimit;
+mod recover;
+mod resumable;
+mod security;
+mod stats;
+mod store;
+mod transform;
+mod ycbcr;
 
 mod mimes {
     use std::str::FromStr;
@@ -26,6 +69,61 @@ mod mimes {
 
 pub const JPEG_QUALITY: u8 = 25;
 
+/// Quality of the images rendered for failed uploads with `--error-images`.
+pub const ERROR_IMAGE_QUALITY: u8 = 90;
+
+/// Seconds clients are asked to wait before fetching a pending image again.
+pub const PENDING_RETRY_AFTER: u64 = 2;
+
+/// Uploads that can wait for the background crusher before `/upload`
+/// starts answering `503 Service Unavailable`.
+pub const CRUSH_QUEUE_LEN: usize = 64;
+
+/// Quality transformed images are stored at when they aren't crushed again.
+/// The source is crushed already; this only keeps what's there.
+pub const TRANSFORM_QUALITY: u8 = 95;
+
+/// Quality of the smaller copy served to clients sending `Save-Data: on`.
+pub const SAVE_DATA_QUALITY: u8 = 10;
+
+/// Quality of the Open Graph crops. They're rescaled, so the crush's own
+/// artifacts are smeared either way, and link preview crawlers give up on
+/// large images; a plain high quality is enough.
+pub const OG_QUALITY: u8 = 90;
+
+/// Most encodes tried when looking for the quality that fits `target_kb`.
+/// Enough to narrow 1..=100 down to a single quality.
+pub const TARGET_SIZE_STEPS: u32 = 7;
+
+/// Variants in a contact sheet when `variants` isn't given.
+pub const DEFAULT_VARIANTS: u32 = 9;
+/// Most variants a contact sheet may have.
+pub const MAX_VARIANTS: u32 = 16;
+/// Longest side, in pixels, of each variant in a contact sheet.
+pub const CONTACT_SHEET_CELL: u32 = 512;
+
+/// Longest side, in pixels, of the blurred previews stored with `--lqip`.
+pub const LQIP_SIZE: u32 = 16;
+/// Blur applied to the previews, which get stretched a lot when shown.
+pub const LQIP_BLUR: f32 = 1.0;
+/// Quality of the previews; they're meant to be blurry anyway.
+pub const LQIP_QUALITY: u8 = 30;
+
+/// With `--oversize-policy downscale`, how many times `--max-pixels` an
+/// upload may still have; it has to be decoded at full size first.
+pub const OVERSIZE_DECODE_FACTOR: u64 = 4;
+
+/// How often abandoned chunked uploads are looked for.
+pub const RESUMABLE_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often clients back at their full `--upload-byte-rate` budget are
+/// forgotten.
+pub const RATE_LIMIT_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long the background crusher waits before trying again to reserve
+/// memory for a crush.
+pub const CRUSH_MEMORY_RETRY: Duration = Duration::from_millis(100);
+
 pub type TemplateMap = HashMap<String, Template>;
 
 #[derive(Debug, thiserror::Error)]
@@ -40,23 +138,592 @@ enum TemplateError {
 enum ImageError {
     #[error("invalid image id")]
     InvalidId,
-}
-
-#[derive(Debug, Clone)]
-struct Image {
-    mime: Mime,
-    contents: Vec<u8>,
+    #[error("image is {width}x{height}, which is more than {max} pixels")]
+    TooManyPixels { width: u32, height: u32, max: u64 },
+    #[error("expected {expected} bytes of pixel data ({width}x{height}x{channels}), got {actual}")]
+    RawSizeMismatch {
+        width: u32,
+        height: u32,
+        channels: u8,
+        expected: u64,
+        actual: usize,
+    },
+    #[error("request would cost {total} crush passes in total, more than the {max} allowed")]
+    TooMuchWork { total: u64, max: u64 },
+    #[error("cannot make {variants} variants, expected 1..={max}")]
+    InvalidVariants { variants: u32, max: u32 },
+    #[error("invalid JPEG quality {0:?}, expected 1..=100")]
+    InvalidQuality(String),
+    #[error("invalid block emphasis {strength}, expected 0..={max}")]
+    InvalidBlockEmphasis { strength: f32, max: f32 },
+    #[error("invalid {component} scale {scale}, expected more than 0 and at most 1")]
+    InvalidComponentScale { component: &'static str, scale: f32 },
+    #[error("invalid {component} quality {quality}, expected 1..=100")]
+    InvalidComponentQuality {
+        component: &'static str,
+        quality: u32,
+    },
+    #[error("invalid output size {size}, expected one of {allowed:?}")]
+    InvalidOutputSize { size: u32, allowed: Vec<u32> },
+    #[error("invalid focus {0:?}, expected x,y,width,height")]
+    InvalidFocus(String),
+    #[error("focus {x},{y},{width},{height} is not within the {image_width}x{image_height} image")]
+    FocusOutOfBounds {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        image_width: u32,
+        image_height: u32,
+    },
+    #[error("request body is larger than {max} bytes")]
+    BodyTooLarge { max: u64 },
+    #[error("sent more than {budget} bytes in {window} seconds, try again in {retry} seconds")]
+    TooManyBytes {
+        budget: u64,
+        window: u64,
+        retry: u64,
+    },
+    #[error("image dimensions must be non-zero")]
+    EmptyImage,
+    #[error("unsupported channel count {0}, expected 1, 3 or 4")]
+    RawChannels(u8),
+    #[error("crushing a {width}x{height} image needs {needed} bytes, more than the {max} allowed")]
+    CrushTooLarge {
+        width: u32,
+        height: u32,
+        needed: u64,
+        max: u64,
+    },
 }
 
 #[derive(Clone)]
 struct State {
+    config: Arc<Config>,
     templates: Arc<TemplateMap>,
-    images: Arc<RwLock<HashMap<String, Image>>>,
+    images: Arc<ImageStore>,
+    /// JPEG served in place of images that are still being crushed.
+    placeholder: Arc<Vec<u8>>,
+    /// Queue of the background crusher, when crushing is deferred.
+    crush_queue: Option<Sender<CrushJob>>,
+    /// Chunked uploads still being sent.
+    partial_uploads: Arc<PartialUploads>,
+    /// Buffer memory held by crushes in flight.
+    crush_memory: Arc<CrushMemory>,
+    /// Upload bytes each client may still send, with `--upload-byte-rate`.
+    upload_bytes: Option<Arc<ByteRateLimiter>>,
+    counters: Arc<stats::Counters>,
+    reencode: Arc<admin::ReencodeProgress>,
+    /// PNG app icons by file name, with `--pwa`.
+    pwa_icons: Arc<HashMap<String, Vec<u8>>>,
+}
+
+impl State {
+    fn image_src(&self, id: &str, format: OutputFormat) -> String {
+        format!(
+            "{}/images/{}.{}",
+            self.config.base_path,
+            id,
+            format.extension()
+        )
+    }
+
+    fn crush_options(&self) -> CrushOptions {
+        CrushOptions {
+            deterministic: self.config.deterministic,
+            ..Default::default()
+        }
+    }
+
+    /// Comment recording how an image was crushed, and scaled to `size`,
+    /// with `--crush-comment`.
+    fn crush_comment(
+        &self,
+        options: &CrushOptions,
+        report: &CrushReport,
+        quality: u8,
+        size: Option<u32>,
+    ) -> Option<String> {
+        self.config
+            .crush_comment
+            .then(|| report.recipe(options, quality, size))
+    }
+
+    /// Crushes `img` once its buffer memory is reserved (see
+    /// `reserve_crush`), and records how long it took.
+    fn crush(
+        &self,
+        img: DynamicImage,
+        options: &CrushOptions,
+        wait: bool,
+    ) -> tide::Result<(DynamicImage, CrushReport)> {
+        check_focus(options.focus, img.width(), img.height())?;
+        let _reservation = self.reserve_crush(&img, wait)?;
+        let (img, report) = img.bitcrush(options)?;
+        self.counters.record_crush(report.elapsed);
+        Ok((img, report))
+    }
+
+    /// Reserves the buffer memory for crushing `img`. When the memory is in
+    /// use by other crushes, either fails with `503 Service Unavailable` or,
+    /// with `wait`, blocks the thread until it's free.
+    fn reserve_crush(&self, img: &DynamicImage, wait: bool) -> tide::Result<Reservation> {
+        let (width, height) = (img.width(), img.height());
+        let needed = crush::peak_memory(width, height);
+        if !self.crush_memory.fits(needed) {
+            return Err(tide::Error::new(
+                StatusCode::PayloadTooLarge,
+                ImageError::CrushTooLarge {
+                    width,
+                    height,
+                    needed,
+                    max: self.crush_memory.max().unwrap_or(u64::MAX),
+                },
+            ));
+        }
+        loop {
+            match self.crush_memory.try_reserve(needed) {
+                Some(reservation) => return Ok(reservation),
+                None if wait => std::thread::sleep(CRUSH_MEMORY_RETRY),
+                None => {
+                    return Err(tide::Error::from_str(
+                        StatusCode::ServiceUnavailable,
+                        "too many images being crushed at once, try again later",
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Crush options for an upload, validated against the configured limits.
+    fn upload_crush_options(&self, query: &UploadQuery) -> tide::Result<CrushOptions> {
+        let passes = match query.budget_ms {
+            Some(_) => crush::MAX_BUDGET_PASSES,
+            None => crush::DEFAULT_PASSES,
+        };
+        if let Some(strength) = query.block_emphasis {
+            let max = crush::MAX_BLOCK_EMPHASIS;
+            if !(0.0..=max).contains(&strength) {
+                return Err(tide::Error::new(
+                    StatusCode::BadRequest,
+                    ImageError::InvalidBlockEmphasis { strength, max },
+                ));
+            }
+        }
+        if query.debug && !self.config.crush_debug {
+            return Err(tide::Error::from_str(
+                StatusCode::Forbidden,
+                "crush debugging is not enabled on this server",
+            ));
+        }
+        let variants = contact_sheet_variants(query)?.unwrap_or(1);
+        self.check_total_passes(&[passes, variants])?;
+        Ok(CrushOptions {
+            passes,
+            budget: query.budget_ms.map(Duration::from_millis),
+            block_emphasis: query.block_emphasis,
+            ycbcr: ycbcr_crush(query)?,
+            trace: query.debug,
+            focus: query.focus.as_deref().map(parse_focus).transpose()?,
+            ..self.crush_options()
+        })
+    }
+
+    /// Crushes `img` `variants` times over, each with its own seed, and tiles
+    /// the results into a contact sheet. With `--deterministic`, the seeds
+    /// count up from the fixed one, so the variants still differ. Each
+    /// variant is shrunk to its cell before the next is crushed. The report
+    /// is the first variant's.
+    fn crush_contact_sheet(
+        &self,
+        img: DynamicImage,
+        options: &CrushOptions,
+        variants: u32,
+        wait: bool,
+    ) -> tide::Result<(DynamicImage, CrushReport)> {
+        let mut cells = Vec::with_capacity(variants as usize);
+        let mut first = None;
+        for i in 0..variants {
+            let options = CrushOptions {
+                seed: options
+                    .deterministic
+                    .then(|| crush::DETERMINISTIC_SEED.wrapping_add(i as u64)),
+                ..options.clone()
+            };
+            let (variant, report) = self.crush(img.clone(), &options, wait)?;
+            cells.push(transform::contact_sheet_cell(&variant, CONTACT_SHEET_CELL));
+            first.get_or_insert(report);
+        }
+        let sheet = transform::contact_sheet(&cells);
+        Ok((sheet, first.expect("at least one variant")))
+    }
+
+    /// Crushes an upload, into a contact sheet of `variants` if asked for.
+    fn crush_upload_image(
+        &self,
+        img: DynamicImage,
+        options: &CrushOptions,
+        variants: Option<u32>,
+        wait: bool,
+    ) -> tide::Result<(DynamicImage, CrushReport)> {
+        match variants {
+            Some(variants) => self.crush_contact_sheet(img, options, variants, wait),
+            None => self.crush(img, options, wait),
+        }
+    }
+
+    /// Rejects a request whose work multipliers (passes per crush, crushes
+    /// per request, ...) multiply out to more than `--max-total-passes`.
+    fn check_total_passes(&self, factors: &[u32]) -> tide::Result<()> {
+        let total = factors
+            .iter()
+            .fold(1u64, |total, &factor| total.saturating_mul(factor as u64));
+        let max = self.config.max_total_passes;
+        if total > max {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                ImageError::TooMuchWork { total, max },
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Number of variants in the contact sheet an upload asks for, if any.
+fn contact_sheet_variants(query: &UploadQuery) -> tide::Result<Option<u32>> {
+    if !query.contact_sheet {
+        return match query.variants {
+            Some(_) => Err(tide::Error::from_str(
+                StatusCode::BadRequest,
+                "variants are only available as a contact sheet, with contact_sheet=true",
+            )),
+            None => Ok(None),
+        };
+    }
+    let variants = query.variants.unwrap_or(DEFAULT_VARIANTS);
+    if !(1..=MAX_VARIANTS).contains(&variants) {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            ImageError::InvalidVariants {
+                variants,
+                max: MAX_VARIANTS,
+            },
+        ));
+    }
+    Ok(Some(variants))
+}
+
+/// Rejects a `focus` rectangle that isn't within a `width`x`height` image.
+fn check_focus(focus: Option<Focus>, width: u32, height: u32) -> tide::Result<()> {
+    match focus {
+        Some(focus) if !focus.fits(width, height) => Err(tide::Error::new(
+            StatusCode::BadRequest,
+            ImageError::FocusOutOfBounds {
+                x: focus.x,
+                y: focus.y,
+                width: focus.width,
+                height: focus.height,
+                image_width: width,
+                image_height: height,
+            },
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Parses a `focus` rectangle given as `x,y,width,height`.
+fn parse_focus(focus: &str) -> tide::Result<Focus> {
+    let invalid = || {
+        tide::Error::new(
+            StatusCode::BadRequest,
+            ImageError::InvalidFocus(focus.to_string()),
+        )
+    };
+    let values = focus
+        .split(',')
+        .map(|value| value.trim().parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid())?;
+    match values[..] {
+        [x, y, width, height] if width > 0 && height > 0 => Ok(Focus {
+            x,
+            y,
+            width,
+            height,
+        }),
+        _ => Err(invalid()),
+    }
+}
+
+/// Per-component crush settings asked for by an upload, if any.
+fn ycbcr_crush(query: &UploadQuery) -> tide::Result<Option<YCbCrCrush>> {
+    let asked = query.ycbcr
+        || query.luma_scale.is_some()
+        || query.luma_quality.is_some()
+        || query.chroma_scale.is_some()
+        || query.chroma_quality.is_some();
+    if !asked {
+        return Ok(None);
+    }
+    let defaults = YCbCrCrush::default();
+    Ok(Some(YCbCrCrush {
+        luma: component_crush("luma", query.luma_scale, query.luma_quality, defaults.luma)?,
+        chroma: component_crush(
+            "chroma",
+            query.chroma_scale,
+            query.chroma_quality,
+            defaults.chroma,
+        )?,
+    }))
+}
+
+fn component_crush(
+    component: &'static str,
+    scale: Option<f32>,
+    quality: Option<u32>,
+    defaults: ComponentCrush,
+) -> tide::Result<ComponentCrush> {
+    let scale = scale.unwrap_or(defaults.scale);
+    if !(scale > 0.0 && scale <= 1.0) {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            ImageError::InvalidComponentScale { component, scale },
+        ));
+    }
+    let quality = match quality {
+        Some(quality) if (1..=100).contains(&quality) => quality as u8,
+        Some(quality) => {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                ImageError::InvalidComponentQuality { component, quality },
+            ))
+        }
+        None => defaults.quality,
+    };
+    Ok(ComponentCrush { scale, quality })
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct UploadQuery {
+    /// Crush for at most this long, up to `crush::MAX_BUDGET_PASSES` passes.
+    budget_ms: Option<u64>,
+    /// Final JPEG quality, overriding the `X-Jpeg-Quality` header.
+    quality: Option<u32>,
+    /// Exaggerate a JPEG input's 8x8 blocking by this factor.
+    block_emphasis: Option<f32>,
+    /// Also return the crushed image in the response.
+    encoding: Option<UploadEncoding>,
+    /// Finish by crushing luma and chroma separately. Implied by any of the
+    /// per-component parameters below, which default to sharp luma and
+    /// smeared chroma.
+    ycbcr: bool,
+    luma_scale: Option<f32>,
+    luma_quality: Option<u32>,
+    chroma_scale: Option<f32>,
+    chroma_quality: Option<u32>,
+    /// Also return a breakdown of the crush, with `--crush-debug`.
+    debug: bool,
+    /// Return a single image tiling this many independently crushed
+    /// variants, with `contact_sheet`.
+    variants: Option<u32>,
+    /// Store a contact sheet of `variants` (9 by default) instead of a
+    /// single crush.
+    contact_sheet: bool,
+    /// Put this rectangle, as `x,y,width,height`, back over the crushed
+    /// image untouched, so it stays sharp.
+    focus: Option<String>,
+    /// Resize the crushed image to this many pixels on its longest side,
+    /// one of `--output-sizes`.
+    size: Option<u32>,
+    /// Instead of `quality` (and not together with it), use the highest
+    /// final quality that keeps the file within this many KiB, comment
+    /// included (or the lowest, if none does).
+    target_kb: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+enum UploadEncoding {
+    #[serde(rename = "datauri")]
+    DataUri,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct EncodeQuery {
+    quality: Option<u32>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct PaletteQuery {
+    /// Number of colors, clamped to `1..=palette::MAX_COLORS`.
+    n: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct ReencodeQuery {
+    quality: u32,
+}
+
+#[derive(Deserialize)]
+struct ResetLimitsQuery {
+    /// Client address, or `all`.
+    ip: String,
+}
+
+#[derive(Deserialize)]
+struct RawQuery {
+    width: u32,
+    height: u32,
+    channels: u8,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ListOrder {
+    #[default]
+    Newest,
+    Oldest,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct ListQuery {
+    order: ListOrder,
 }
 
 #[derive(Serialize)]
+struct ListEntry {
+    id: String,
+    src: String,
+}
+
+#[derive(Serialize)]
+struct ListResponse {
+    images: Vec<ListEntry>,
+}
+
+#[derive(Default, Serialize)]
 struct UploadResponse<'a> {
     src: &'a str,
+    /// Crush passes actually applied, when the request crushed anything.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    passes: Option<u32>,
+    /// Final JPEG quality picked for `target_kb`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quality: Option<u8>,
+    /// Size of the stored file, with `target_kb`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<usize>,
+    /// `"pending"` when the crush happens in the background.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<&'static str>,
+    /// The crushed image itself, with `encoding=datauri` and when it's small
+    /// enough.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data_uri: Option<String>,
+    /// How the crush went, with `debug=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debug: Option<CrushDebug>,
+}
+
+#[derive(Serialize)]
+struct CrushDebug {
+    decode_ms: f64,
+    /// Every pass, plus the block emphasis and YCbCr crush when asked for.
+    crush_ms: f64,
+    encode_ms: f64,
+    seed: u64,
+    /// Size of the decoded input.
+    width: u32,
+    height: u32,
+    passes: Vec<PassDebug>,
+    /// Size of the stored file.
+    bytes: usize,
+}
+
+#[derive(Serialize)]
+struct PassDebug {
+    /// Intermediate size the image was resized to.
+    width: u32,
+    height: u32,
+    /// Quality of the intermediate JPEG.
+    quality: u8,
+    /// Size of the intermediate JPEG.
+    bytes: usize,
+    ms: f64,
+}
+
+#[derive(Serialize)]
+struct MetaResponse<'a> {
+    id: &'a str,
+    src: String,
+    /// `"pending"` until the background crusher is done, then `"ready"`.
+    status: &'static str,
+    /// Size of the stored file.
+    bytes: usize,
+    /// Blurred preview as a data URI, with `--lqip`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lqip: Option<String>,
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    /// Stored images, pending ones included.
+    images: usize,
+    /// Images still waiting for the background crusher.
+    pending: usize,
+    /// Size of every stored file together.
+    bytes: usize,
+    /// Images stored since the server started.
+    uploads: u64,
+    /// Images dropped since the server started, e.g. deferred crushes that
+    /// failed.
+    evictions: u64,
+    /// Uploads that couldn't be decoded, by sniffed input format.
+    decode_failures: BTreeMap<String, u64>,
+    crush_memory: CrushMemoryStats,
+}
+
+#[derive(Serialize)]
+struct TimeseriesResponse {
+    bucket_seconds: u64,
+    buckets: Vec<stats::TimeseriesPoint>,
+}
+
+#[derive(Serialize)]
+struct CrushMemoryStats {
+    /// Bytes reserved by crushes in flight.
+    used: u64,
+    /// `--max-crush-memory`, or `null` when unlimited.
+    max: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ResetLimitsResponse<'a> {
+    ip: &'a str,
+    /// Clients whose limits were cleared; those already at their full
+    /// budget aren't tracked and don't count.
+    reset: usize,
+}
+
+#[derive(Serialize)]
+struct ResumableResponse<'a> {
+    upload_id: &'a str,
+    /// Bytes received so far.
+    offset: u64,
+}
+
+/// An upload waiting to be crushed in the background.
+struct CrushJob {
+    id: String,
+    options: CrushOptions,
+    quality: u8,
+    /// Longest side to resize the crushed image to.
+    size: Option<u32>,
+    /// Variants to tile into a contact sheet.
+    variants: Option<u32>,
 }
 
 trait ForTide {
@@ -75,48 +742,9 @@ impl ForTide for Result<tide::Response, Box<dyn Error>> {
     }
 }
 
-trait BitCrush: Sized {
-    type Error;
-
-    fn bitcrush(self) -> Result<Self, Self::Error>;
-}
-
-impl BitCrush for DynamicImage {
-    type Error = image::ImageError;
-
-    fn bitcrush(self) -> Result<Self, Self::Error> {
-        let mut current = self;
-        let (orig_w, orig_h) = current.dimensions();
-
-        let mut rng = rand::thread_rng();
-        let (temp_w, temp_h) = (
-            rng.gen_range(orig_w / 2..orig_w * 2),
-            rng.gen_range(orig_h / 2..orig_h * 2),
-        );
-
-        let mut out: Vec<u8> = Default::default();
-        for _ in 0..2 {
-            current = current
-                .resize_exact(temp_w, temp_h, FilterType::Nearest)
-                .rotate180()
-                .huerotate(180);
-            out.clear();
-            {
-                let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(
-                    &mut out,
-                    rng.gen_range(10..30),
-                );
-                encoder.encode_image(&current)?;
-            }
-            current = image::load_from_memory_with_format(&out[..], image::ImageFormat::Jpeg)?
-                .resize_exact(orig_w, orig_h, FilterType::Nearest);
-        }
-        Ok(current)
-    }
-}
-
 #[async_std::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    let config = Config::parse();
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var("RUST_LOG", "info");
     }
@@ -131,63 +759,176 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let templates = Arc::new(templates);
     log::info!("{} templates compiled", templates.len());
 
+    if config.deterministic {
+        log::info!("Deterministic mode enabled, every crush uses a fixed seed");
+    }
+
+    let placeholder = match &config.processing_placeholder {
+        Some(path) => async_std::fs::read(path).await?,
+        None => encode_jpeg(
+            &DynamicImage::ImageRgb8(banner::render(
+                "Processing, try again in a moment",
+                banner::PENDING,
+            )),
+            ERROR_IMAGE_QUALITY,
+        )?,
+    };
+
+    let (crush_queue, crush_jobs) = if config.deferred_crush {
+        log::info!("Deferred crushing enabled, uploads are crushed in the background");
+        let (sender, receiver) = channel::bounded(CRUSH_QUEUE_LEN);
+        (Some(sender), Some(receiver))
+    } else {
+        (None, None)
+    };
+
+    let mut pwa_icons = HashMap::new();
+    if config.pwa {
+        for size in pwa::ICON_SIZES {
+            let icon = pwa::render_icon(size, config.theme_color)?;
+            pwa_icons.insert(pwa::icon_name(size), icon);
+        }
+        log::info!("Serving a web app manifest and {} icons", pwa_icons.len());
+    }
+
     let state = State {
+        placeholder: Arc::new(placeholder),
+        crush_queue,
         templates,
         images: Default::default(),
+        partial_uploads: Default::default(),
+        crush_memory: Arc::new(CrushMemory::new(config.max_crush_memory)),
+        upload_bytes: config.upload_byte_rate.map(|rate| {
+            let window = Duration::from_secs(config.upload_byte_window);
+            Arc::new(ByteRateLimiter::new(rate, window))
+        }),
+        counters: Default::default(),
+        reencode: Default::default(),
+        pwa_icons: Arc::new(pwa_icons),
+        config: Arc::new(config),
     };
 
-    let mut app = tide::with_state(state);
+    if let Some(jobs) = crush_jobs {
+        async_std::task::spawn(crush_worker(state.clone(), jobs));
+    }
+    async_std::task::spawn(expire_partial_uploads(state.clone()));
+    if let Some(limiter) = &state.upload_bytes {
+        log::info!(
+            "Limiting uploads to {} bytes per client every {} seconds",
+            limiter.budget(),
+            state.config.upload_byte_window
+        );
+        async_std::task::spawn(expire_rate_limits(Arc::clone(limiter)));
+    }
+    if let Some(interval) = state.config.stats_log_interval {
+        async_std::task::spawn(log_stats(state.clone(), Duration::from_secs(interval)));
+    }
 
-    app.at("/").get(|req: Request<State>| async move {
-        serve_template(&req.state().templates, "index.html", mimes::html())
-            .await
-            .for_tide()
-    });
-
-    app.at("/style.css").get(|req: Request<State>| async move {
-        serve_template(&req.state().templates, "style.css", mimes::css())
-            .await
-            .for_tide()
+    let security_headers = state.config.security_headers;
+    let chaos = state.config.chaos.map(|percent| chaos::Chaos {
+        percent,
+        max_delay: Duration::from_millis(state.config.chaos_delay),
+        base_path: state.config.base_path.clone(),
     });
+    let access_log = access_log::AccessLog {
+        base_path: state.config.base_path.clone(),
+        routes: state.config.log_route.clone(),
+    };
+    let mut app = tide::with_state(state);
+    app.with(access_log);
+    app.with(recover::CatchPanic);
+    if security_headers {
+        app.with(security::SecurityHeaders);
+    }
+    if let Some(chaos) = chaos {
+        log::warn!(
+            "CHAOS MODE: {}% of uploads will fail and image fetches may be delayed by up to {:?}. Never run this in production!",
+            chaos.percent,
+            chaos.max_delay
+        );
+        app.with(chaos);
+    }
+    app.with(After(|mut res: Response| async move {
+        if res.status().is_client_error() {
+            if let Some(message) = res.error().map(|e| e.to_string()) {
+                res.set_body(message);
+            }
+        }
+        Ok(res)
+    }));
 
-    app.at("/main.js").get(|req: Request<State>| async move {
-        serve_template(&req.state().templates, "main.js", mimes::js())
+    let base_path = app.state().config.base_path.clone();
+    let route = |path: &str| format!("{}{}", base_path, path);
+    if !base_path.is_empty() {
+        app.at(&base_path).get(|req: Request<State>| async move {
+            serve_template(req.state(), "index.html", mimes::html())
+                .await
+                .for_tide()
+        });
+    }
+    app.at(&route("/")).get(|req: Request<State>| async move {
+        serve_template(req.state(), "index.html", mimes::html())
             .await
             .for_tide()
     });
 
-    app.at("/upload")
-        .post(|mut req: Request<State>| async move {
-            let body = req.body_bytes().await?;
-            let img = image::load_from_memory(&body[..])?.bitcrush()?;
-            let mut output: Vec<u8> = Default::default();
-            let mut encoder =
-                image::codecs::jpeg::JpegEncoder::new_with_quality(&mut output, JPEG_QUALITY);
-            encoder.encode_image(&img)?;
-
-            let id = Ulid::new();
-            let src = format!("/images/{}.jpg", id.to_string());
-
-            log::info!("src: {}", &src);
+    app.at(&route("/style.css"))
+        .get(|req: Request<State>| async move {
+            serve_template(req.state(), "style.css", mimes::css())
+                .await
+                .for_tide()
+        });
 
-            let img = Image {
-                mime: tide::http::mime::JPEG,
-                contents: output,
-            };
+    app.at(&route("/main.js"))
+        .get(|req: Request<State>| async move {
+            serve_template(req.state(), "main.js", mimes::js())
+                .await
+                .for_tide()
+        });
 
-            {
-                let rw = req.state().images.clone();
-                let mut images = rw.write().await;
-                images.insert(id.to_string(), img);
-            }
+    if app.state().config.pwa {
+        app.at(&route("/manifest.json")).get(serve_manifest);
+        app.at(&route("/icons/:name")).get(serve_icon);
+    }
 
-            let mut res = Response::new(StatusCode::Ok);
-            res.set_content_type(tide::http::mime::JSON);
-            res.set_body(tide::Body::from_json(&UploadResponse { src: &src })?);
-            Ok(res)
+    app.at(&route("/upload")).post(|req: Request<State>| async {
+        let enabled = req.state().config.error_images;
+        or_error_image(upload(req), enabled).await
+    });
+    app.at(&route("/upload/init")).post(start_resumable_upload);
+    app.at(&route("/upload/:uid"))
+        .get(resumable_upload_offset)
+        .patch(append_resumable_upload)
+        .post(|req: Request<State>| async {
+            let enabled = req.state().config.error_images;
+            or_error_image(finish_resumable_upload(req), enabled).await
+        });
+    app.at(&route("/upload/raw"))
+        .post(|req: Request<State>| async {
+            let enabled = req.state().config.error_images;
+            or_error_image(upload_raw(req), enabled).await
         });
-    app.at("/images/:name")
+    app.at(&route("/encode")).post(|req: Request<State>| async {
+        let enabled = req.state().config.error_images;
+        or_error_image(encode(req), enabled).await
+    });
+    app.at(&route("/images")).get(list_images);
+    app.at(&route("/stats")).get(serve_stats);
+    app.at(&route("/stats/timeseries")).get(serve_timeseries);
+    app.at(&route("/admin/reencode"))
+        .get(reencode_status)
+        .post(start_reencode);
+    app.at(&route("/admin/reset-limits")).post(reset_limits);
+    app.at(&route("/images/:name"))
         .get(|req: Request<State>| async { serve_image(req).await.for_tide() });
+    app.at(&route("/images/:name/thumb"))
+        .get(|req: Request<State>| async { serve_thumbnail(req).await.for_tide() });
+    app.at(&route("/images/:name/og"))
+        .get(|req: Request<State>| async { serve_og(req).await.for_tide() });
+    app.at(&route("/images/:name/meta")).get(serve_meta);
+    app.at(&route("/images/:name/palette")).get(serve_palette);
+    app.at(&route("/images/:name/transform"))
+        .post(transform_image);
     app.listen("0.0.0.0:3000").await?;
     Ok(())
 }
@@ -197,8 +938,8 @@ async fn compile_templates(paths: &[&str]) -> Result<TemplateMap, Box<dyn Error>
     let mut map = TemplateMap::new();
     for path in paths {
         let name = path
-            .split('/')
-            .last()
+            .rsplit('/')
+            .next()
             .map(|name| name.trim_end_matches(".liquid"))
             .ok_or_else(|| TemplateError::InvalidTemplatePath(path.to_string()))?;
         let source = read_to_string(path).await?;
@@ -208,15 +949,16 @@ async fn compile_templates(paths: &[&str]) -> Result<TemplateMap, Box<dyn Error>
     Ok(map)
 }
 
-async fn serve_template(
-    templates: &TemplateMap,
-    name: &str,
-    mime: Mime,
-) -> Result<Response, Box<dyn Error>> {
-    let template = templates
+async fn serve_template(state: &State, name: &str, mime: Mime) -> Result<Response, Box<dyn Error>> {
+    let template = state
+        .templates
         .get(name)
         .ok_or_else(|| TemplateError::InvalidTemplate(name.to_string()))?;
-    let globals: Object = Default::default();
+    let globals: Object = liquid::object!({
+        "base_path": state.config.base_path,
+        "pwa": state.config.pwa,
+        "theme_color": palette::hex(state.config.theme_color),
+    });
     let markup = template.render(&globals)?;
     let mut res = Response::new(StatusCode::Ok);
     res.set_content_type(mime);
@@ -224,18 +966,1407 @@ async fn serve_template(
     Ok(res)
 }
 
+async fn serve_manifest(req: Request<State>) -> tide::Result {
+    let config = &req.state().config;
+    let manifest = pwa::Manifest::new(&config.app_name, config.theme_color, &config.base_path);
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(tide::Body::from_json(&manifest)?);
+    res.set_content_type(Mime::from("application/manifest+json"));
+    Ok(res)
+}
+
+async fn serve_icon(req: Request<State>) -> tide::Result {
+    let name = req.param("name")?;
+    let icon = match req.state().pwa_icons.get(name) {
+        Some(icon) => icon.clone(),
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+    let mut res = Response::new(StatusCode::Ok);
+    res.insert_header("Cache-Control", "public, max-age=86400");
+    res.set_content_type(tide::http::mime::PNG);
+    res.set_body(icon);
+    Ok(res)
+}
+
+async fn upload(mut req: Request<State>) -> tide::Result {
+    let query: UploadQuery = req.query()?;
+    let upload = check_upload_query(&req, &query)?;
+    let body = read_body(&mut req).await?;
+    crush_upload(&req, upload, &query, body).await
+}
+
+/// What an upload's query parameters and headers ask for, checked before
+/// its body is read.
+struct UploadRequest {
+    options: CrushOptions,
+    quality: u8,
+    /// Largest the encoded image may be, in bytes, for `target_kb`.
+    target_size: Option<u64>,
+    size: Option<u32>,
+    variants: Option<u32>,
+}
+
+fn check_upload_query(req: &Request<State>, query: &UploadQuery) -> tide::Result<UploadRequest> {
+    let target_size = match query.target_kb {
+        Some(0) => {
+            return Err(tide::Error::from_str(
+                StatusCode::BadRequest,
+                "target_kb must be at least 1",
+            ))
+        }
+        Some(_) if query.quality.is_some() || req.header("X-Jpeg-Quality").is_some() => {
+            return Err(tide::Error::from_str(
+                StatusCode::BadRequest,
+                "target_kb picks the quality itself, it can't be combined with quality or X-Jpeg-Quality",
+            ))
+        }
+        target_kb => target_kb.map(|kb| kb.saturating_mul(1024)),
+    };
+    Ok(UploadRequest {
+        options: req.state().upload_crush_options(query)?,
+        quality: output_quality(req, query.quality)?,
+        target_size,
+        size: output_size(req.state(), query.size)?,
+        variants: contact_sheet_variants(query)?,
+    })
+}
+
+/// Crushes and stores an uploaded image, or queues it when crushing is
+/// deferred. `query` is only used for how the result is returned; what to
+/// do with the image is in `upload`.
+async fn crush_upload(
+    req: &Request<State>,
+    upload: UploadRequest,
+    query: &UploadQuery,
+    body: Vec<u8>,
+) -> tide::Result {
+    let UploadRequest {
+        mut options,
+        quality,
+        target_size,
+        size,
+        variants,
+    } = upload;
+    // A debug breakdown or a size search only exists once the crush is
+    // done, so those uploads are never deferred.
+    let queue = req
+        .state()
+        .crush_queue
+        .as_ref()
+        .filter(|_| !options.trace && target_size.is_none());
+    if let Some(queue) = queue {
+        // Checked here too so a bad rectangle gets an answer, rather than the
+        // worker dropping the image. HEIF dimensions are only known once
+        // decoded, so those are left to the worker.
+        if let Some((width, height)) = check_upload(req.state(), &body)?.dimensions {
+            let (width, height) = fitted_dimensions(req.state(), width, height);
+            check_focus(options.focus, width, height)?;
+        }
+        let id = Ulid::new().to_string();
+        let src = req.state().image_src(&id, OutputFormat::Jpeg);
+        let pending = Image {
+            status: ImageStatus::Pending,
+            format: OutputFormat::Jpeg,
+            contents: body,
+            thumbnail: None,
+            lqip: None,
+            save_data: None,
+            og: None,
+            palettes: HashMap::new(),
+            generation: 0,
+        };
+        req.state().images.insert(id.clone(), pending).await;
+        let job = CrushJob {
+            id: id.clone(),
+            options,
+            quality,
+            size,
+            variants,
+        };
+        if queue.try_send(job).is_err() {
+            req.state().images.remove(&id).await;
+            return Err(tide::Error::from_str(
+                StatusCode::ServiceUnavailable,
+                "too many uploads waiting to be crushed, try again later",
+            ));
+        }
+        req.state().counters.record_upload();
+        log::info!("src: {} (pending)", &src);
+        return upload_response(UploadResponse {
+            src: &src,
+            status: Some("pending"),
+            ..Default::default()
+        });
+    }
+
+    let decode_start = Instant::now();
+    let (img, format) = decode_upload(req.state(), &body)?;
+    let decode_time = decode_start.elapsed();
+    if format != Some(image::ImageFormat::Jpeg) {
+        // No block grid to line up with.
+        options.block_emphasis = None;
+    }
+    let (width, height) = (img.width(), img.height());
+    let (img, report) = req
+        .state()
+        .crush_upload_image(img, &options, variants, false)?;
+    let img = resize_output(img, size);
+    // A contact sheet's variants each have their own recipe.
+    let comment = |quality| {
+        req.state()
+            .crush_comment(&options, &report, quality, size)
+            .filter(|_| variants.is_none())
+    };
+    let quality = match target_size {
+        Some(max_bytes) => quality_for_size(&img, max_bytes, comment)?,
+        None => quality,
+    };
+    let comment = comment(quality);
+    let encode_start = Instant::now();
+    let image = encode_image(req.state(), &img, quality, comment.as_deref())?;
+    let debug = options.trace.then(|| CrushDebug {
+        decode_ms: millis(decode_time),
+        crush_ms: millis(report.elapsed),
+        encode_ms: millis(encode_start.elapsed()),
+        seed: report.seed,
+        width,
+        height,
+        passes: report
+            .trace
+            .iter()
+            .map(|pass| PassDebug {
+                width: pass.width,
+                height: pass.height,
+                quality: pass.quality,
+                bytes: pass.bytes,
+                ms: millis(pass.elapsed),
+            })
+            .collect(),
+        bytes: image.contents.len(),
+    });
+    let fitted = target_size.map(|_| (quality, image.contents.len()));
+    let data_uri = match query.encoding {
+        Some(UploadEncoding::DataUri)
+            if image.contents.len() <= req.state().config.max_data_uri_size =>
+        {
+            Some(data_uri(image.format, &image.contents))
+        }
+        _ => None,
+    };
+    let src = insert_image(req.state(), image).await;
+    upload_response(UploadResponse {
+        src: &src,
+        passes: Some(report.passes),
+        quality: fitted.map(|(quality, _)| quality),
+        bytes: fitted.map(|(_, bytes)| bytes),
+        data_uri,
+        debug,
+        ..Default::default()
+    })
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Starts a chunked upload. Chunks are then sent with `PATCH /upload/:uid`
+/// and an `Upload-Offset` header, and `POST /upload/:uid` crushes the result.
+async fn start_resumable_upload(req: Request<State>) -> tide::Result {
+    let uid = req.state().partial_uploads.start().await;
+    log::debug!("Started resumable upload {}", uid);
+    let mut res = Response::new(StatusCode::Created);
+    res.insert_header("Upload-Offset", "0");
+    res.set_content_type(tide::http::mime::JSON);
+    res.set_body(tide::Body::from_json(&ResumableResponse {
+        upload_id: &uid,
+        offset: 0,
+    })?);
+    Ok(res)
+}
+
+/// How much of a chunked upload the server has, to resume from after a
+/// dropped connection. Also answers `HEAD`.
+async fn resumable_upload_offset(req: Request<State>) -> tide::Result {
+    let uid = req.param("uid")?;
+    let offset = match req.state().partial_uploads.offset(uid).await {
+        Some(offset) => offset,
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+    let mut res = Response::new(StatusCode::Ok);
+    res.insert_header("Upload-Offset", offset.to_string());
+    res.insert_header("Cache-Control", "no-store");
+    res.set_content_type(tide::http::mime::JSON);
+    res.set_body(tide::Body::from_json(&ResumableResponse {
+        upload_id: uid,
+        offset,
+    })?);
+    Ok(res)
+}
+
+/// Appends the body to a chunked upload at the offset given in the
+/// `Upload-Offset` header, which has to match what the server already has.
+async fn append_resumable_upload(mut req: Request<State>) -> tide::Result {
+    let offset = req
+        .header("Upload-Offset")
+        .and_then(|values| values.as_str().trim().parse::<u64>().ok())
+        .ok_or_else(|| {
+            tide::Error::from_str(
+                StatusCode::BadRequest,
+                "missing or invalid Upload-Offset header",
+            )
+        })?;
+    let max = req.state().config.max_resumable_size;
+    let received = match req.state().partial_uploads.offset(req.param("uid")?).await {
+        Some(received) => received,
+        None => {
+            return Err(tide::Error::new(
+                StatusCode::NotFound,
+                AppendError::NotFound,
+            ))
+        }
+    };
+    let chunk = read_body_limited(&mut req, Some(max.saturating_sub(received))).await?;
+    let state = req.state();
+    let uid = req.param("uid")?;
+    let offset = state
+        .partial_uploads
+        .append(uid, offset, &chunk, max)
+        .await
+        .map_err(|e| {
+            let status = match e {
+                AppendError::NotFound => StatusCode::NotFound,
+                AppendError::OffsetMismatch { .. } => StatusCode::Conflict,
+                AppendError::TooLarge { .. } => StatusCode::PayloadTooLarge,
+            };
+            tide::Error::new(status, e)
+        })?;
+    let mut res = Response::new(StatusCode::NoContent);
+    res.insert_header("Upload-Offset", offset.to_string());
+    Ok(res)
+}
+
+/// Crushes a finished chunked upload. Takes the same query parameters and
+/// headers as `/upload`.
+async fn finish_resumable_upload(req: Request<State>) -> tide::Result {
+    let query: UploadQuery = req.query()?;
+    let upload = check_upload_query(&req, &query)?;
+    let uid = req.param("uid")?;
+    // Kept until the image is stored, so the upload can be finished again
+    // after a failure, e.g. a `503` while the server is busy.
+    let body = match req.state().partial_uploads.get(uid).await {
+        Some(body) => body,
+        None => {
+            return Err(tide::Error::new(
+                StatusCode::NotFound,
+                AppendError::NotFound,
+            ))
+        }
+    };
+    let res = crush_upload(&req, upload, &query, body).await?;
+    req.state().partial_uploads.remove(uid).await;
+    Ok(res)
+}
+
+/// Checks a requested output size against `--output-sizes`.
+fn output_size(state: &State, size: Option<u32>) -> tide::Result<Option<u32>> {
+    let allowed = &state.config.output_sizes;
+    match size {
+        Some(size) if !allowed.contains(&size) => Err(tide::Error::new(
+            StatusCode::BadRequest,
+            ImageError::InvalidOutputSize {
+                size,
+                allowed: allowed.clone(),
+            },
+        )),
+        size => Ok(size),
+    }
+}
+
+/// Scales a crushed image, up or down, to `size` pixels on its longest side.
+fn resize_output(img: DynamicImage, size: Option<u32>) -> DynamicImage {
+    match size {
+        Some(size) => img.resize(size, size, image::imageops::FilterType::Triangle),
+        None => img,
+    }
+}
+
+/// Periodically drops chunked uploads that were abandoned.
+async fn expire_partial_uploads(state: State) {
+    let ttl = Duration::from_secs(state.config.resumable_ttl);
+    loop {
+        async_std::task::sleep(RESUMABLE_EXPIRY_INTERVAL.min(ttl)).await;
+        let expired = state.partial_uploads.expire(ttl).await;
+        if expired > 0 {
+            log::info!("Dropped {} abandoned resumable upload(s)", expired);
+        }
+    }
+}
+
+/// Periodically forgets clients that are back at their full upload budget.
+async fn expire_rate_limits(limiter: Arc<ByteRateLimiter>) {
+    loop {
+        async_std::task::sleep(RATE_LIMIT_EXPIRY_INTERVAL).await;
+        limiter.expire();
+    }
+}
+
+/// Crushes deferred uploads one at a time, replacing each pending image
+/// with its crushed version (or dropping it if it can't be crushed).
+async fn crush_worker(state: State, jobs: Receiver<CrushJob>) {
+    while let Ok(job) = jobs.recv().await {
+        let original = state
+            .images
+            .read(&job.id, None, |img| img.contents.clone())
+            .await;
+        let original = match original {
+            Some(original) => original,
+            None => continue,
+        };
+
+        let worker_state = state.clone();
+        let CrushJob {
+            id,
+            mut options,
+            quality,
+            size,
+            variants,
+        } = job;
+        let crushed = async_std::task::spawn_blocking(move || -> tide::Result<Image> {
+            let (img, format) = decode_upload(&worker_state, &original)?;
+            if format != Some(image::ImageFormat::Jpeg) {
+                options.block_emphasis = None;
+            }
+            let (img, report) = worker_state.crush_upload_image(img, &options, variants, true)?;
+            let img = resize_output(img, size);
+            let comment = worker_state
+                .crush_comment(&options, &report, quality, size)
+                .filter(|_| variants.is_none());
+            Ok(encode_image(
+                &worker_state,
+                &img,
+                quality,
+                comment.as_deref(),
+            )?)
+        })
+        .await;
+
+        match crushed {
+            Ok(image) => {
+                state.images.replace(&id, image).await;
+                log::info!("Finished crushing {}", id);
+            }
+            Err(e) => {
+                log::warn!("Dropping {}, could not crush it: {}", id, e);
+                state.images.remove(&id).await;
+                state.counters.record_eviction();
+            }
+        }
+    }
+}
+
+/// Re-encodes an upload at the requested quality without crushing it, to
+/// show what plain JPEG quality loss looks like on its own.
+async fn encode(mut req: Request<State>) -> tide::Result {
+    let EncodeQuery { quality } = req.query()?;
+    let quality = output_quality(&req, quality)?;
+    let body = read_body(&mut req).await?;
+    let (img, _) = decode_upload(req.state(), &body)?;
+    let src = store_image(req.state(), &img, quality, None).await?;
+    upload_response(UploadResponse {
+        src: &src,
+        ..Default::default()
+    })
+}
+
+/// Decodes an uploaded image after checking its size, returning it along
+/// with its format when the `image` crate recognized it.
+fn decode_upload(
+    state: &State,
+    body: &[u8],
+) -> tide::Result<(DynamicImage, Option<image::ImageFormat>)> {
+    if heif::is_heif(body) {
+        let img = decode_heif(state, body).inspect_err(|e| {
+            if e.status() == StatusCode::InternalServerError {
+                state.counters.record_decode_failure("heif");
+            }
+        })?;
+        return Ok((fit_pixels(state, color::to_8bit(img)), None));
+    }
+    let format = check_upload(state, body)?.format;
+    let img = if format == Some(image::ImageFormat::Jpeg) && color::is_cmyk_jpeg(body) {
+        color::decode_cmyk_jpeg(body, state.config.cmyk_jpeg)
+    } else {
+        image::load_from_memory(body)
+    }
+    .inspect_err(|_| record_decode_failure(state, format))?;
+    Ok((fit_pixels(state, color::to_8bit(img)), format))
+}
+
+fn record_decode_failure(state: &State, format: Option<image::ImageFormat>) {
+    let format = match format {
+        Some(format) => format!("{:?}", format).to_lowercase(),
+        None => "unknown".to_string(),
+    };
+    log::debug!("Could not decode a {} upload", format);
+    state.counters.record_decode_failure(&format);
+}
+
+/// What `check_upload` learned from an upload's header.
+struct UploadHeader {
+    format: Option<image::ImageFormat>,
+    /// `None` for HEIF files.
+    dimensions: Option<(u32, u32)>,
+}
+
+/// Checks an upload's size from its header alone, without decoding it, and
+/// returns its format and dimensions. HEIF files are only checked once
+/// decoded, or refused right away on a build that can't decode them.
+fn check_upload(state: &State, body: &[u8]) -> tide::Result<UploadHeader> {
+    if heif::is_heif(body) {
+        if !cfg!(feature = "heif") {
+            return Err(heif_unsupported());
+        }
+        return Ok(UploadHeader {
+            format: None,
+            dimensions: None,
+        });
+    }
+    let reader = ImageReader::new(Cursor::new(body)).with_guessed_format()?;
+    let format = reader.format();
+    let (width, height) = reader
+        .into_dimensions()
+        .inspect_err(|_| record_decode_failure(state, format))?;
+    check_pixels(state, width, height)?;
+    if state.config.animated_inputs == AnimatedInputs::Reject
+        && animation::is_animated(body, format)
+    {
+        return Err(tide::Error::from_str(
+            StatusCode::UnsupportedMediaType,
+            "animated images are not accepted by this server",
+        ));
+    }
+    Ok(UploadHeader {
+        format,
+        dimensions: Some((width, height)),
+    })
+}
+
+#[cfg(feature = "heif")]
+fn decode_heif(state: &State, body: &[u8]) -> tide::Result<DynamicImage> {
+    heif::decode(body, |width, height| check_pixels(state, width, height))
+}
+
+#[cfg(not(feature = "heif"))]
+fn decode_heif(_state: &State, _body: &[u8]) -> tide::Result<DynamicImage> {
+    Err(heif_unsupported())
+}
+
+fn heif_unsupported() -> tide::Error {
+    tide::Error::from_str(
+        StatusCode::UnsupportedMediaType,
+        "HEIF/HEIC uploads are not supported by this server (built without the `heif` feature)",
+    )
+}
+
+/// Crushes raw, uncompressed pixel data described by the query parameters.
+async fn upload_raw(mut req: Request<State>) -> tide::Result {
+    let RawQuery {
+        width,
+        height,
+        channels,
+    } = req.query()?;
+    if width == 0 || height == 0 {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            ImageError::EmptyImage,
+        ));
+    }
+    check_pixels(req.state(), width, height)?;
+    let expected = width as u64 * height as u64 * channels as u64;
+    let max = req.state().config.max_raw_upload_bytes;
+    if expected > max {
+        return Err(tide::Error::new(
+            StatusCode::PayloadTooLarge,
+            ImageError::BodyTooLarge { max },
+        ));
+    }
+
+    // Never read more than the dimensions call for, whatever the body says.
+    let body = read_body_limited(&mut req, Some(expected)).await?;
+    if expected != body.len() as u64 {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            ImageError::RawSizeMismatch {
+                width,
+                height,
+                channels,
+                expected,
+                actual: body.len(),
+            },
+        ));
+    }
+
+    let img = match channels {
+        1 => GrayImage::from_raw(width, height, body).map(DynamicImage::ImageLuma8),
+        3 => RgbImage::from_raw(width, height, body).map(DynamicImage::ImageRgb8),
+        4 => RgbaImage::from_raw(width, height, body).map(DynamicImage::ImageRgba8),
+        _ => {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                ImageError::RawChannels(channels),
+            ))
+        }
+    }
+    .expect("buffer length was checked against the dimensions");
+    let img = fit_pixels(req.state(), img);
+
+    let options = req.state().crush_options();
+    let (img, report) = req.state().crush(img, &options, false)?;
+    let comment = req
+        .state()
+        .crush_comment(&options, &report, JPEG_QUALITY, None);
+    let src = store_image(req.state(), &img, JPEG_QUALITY, comment.as_deref()).await?;
+    upload_response(UploadResponse {
+        src: &src,
+        passes: Some(report.passes),
+        ..Default::default()
+    })
+}
+
+/// Final encode quality for an upload: the `quality` query parameter, then
+/// the `X-Jpeg-Quality` header, then the server default.
+fn output_quality(req: &Request<State>, quality: Option<u32>) -> tide::Result<u8> {
+    let requested = match (quality, req.header("X-Jpeg-Quality")) {
+        (Some(quality), _) => quality.to_string(),
+        (None, Some(header)) => header.as_str().trim().to_string(),
+        (None, None) => return Ok(JPEG_QUALITY),
+    };
+    match requested.parse::<u8>() {
+        Ok(quality) if (1..=100).contains(&quality) => Ok(quality),
+        _ => Err(tide::Error::new(
+            StatusCode::BadRequest,
+            ImageError::InvalidQuality(requested),
+        )),
+    }
+}
+
+/// Runs an upload handler, rendering failures to decode or crush the
+/// upload as an image when `--error-images` is on. Everything else keeps its
+/// status: see `is_image_error`.
+async fn or_error_image(
+    handler: impl std::future::Future<Output = tide::Result>,
+    enabled: bool,
+) -> tide::Result {
+    match handler.await {
+        Err(e) if enabled && is_image_error(&e) => {
+            log::warn!("Upload failed, answering with an error image: {}", e);
+            let img = DynamicImage::ImageRgb8(banner::render(&e.to_string(), banner::ERROR));
+            let mut res = Response::new(StatusCode::Ok);
+            res.set_content_type(tide::http::mime::JPEG);
+            res.set_body(encode_jpeg(&img, ERROR_IMAGE_QUALITY)?);
+            Ok(res)
+        }
+        res => res,
+    }
+}
+
+/// Whether an upload failed because of the image itself: it couldn't be
+/// decoded or encoded, isn't a supported format (`415`), or its dimensions
+/// or focus rectangle were rejected. Everything else, bad query parameters
+/// and limits included, keeps its status so clients can see and fix it.
+fn is_image_error(e: &tide::Error) -> bool {
+    if e.status() == StatusCode::UnsupportedMediaType
+        || e.downcast_ref::<image::ImageError>().is_some()
+    {
+        return true;
+    }
+    #[cfg(feature = "heif")]
+    if e.downcast_ref::<heif::HeifDecodeError>().is_some() {
+        return true;
+    }
+    matches!(
+        e.downcast_ref::<ImageError>(),
+        Some(
+            ImageError::TooManyPixels { .. }
+                | ImageError::CrushTooLarge { .. }
+                | ImageError::FocusOutOfBounds { .. }
+                | ImageError::EmptyImage
+                | ImageError::RawSizeMismatch { .. }
+                | ImageError::RawChannels(_)
+        )
+    )
+}
+
+/// Reads the request body, giving up if the client is too slow to send it.
+async fn read_body(req: &mut Request<State>) -> tide::Result<Vec<u8>> {
+    read_body_limited(req, None).await
+}
+
+/// Like `read_body`, but fails with `413 Payload Too Large` as soon as the
+/// body turns out to be longer than `max` bytes, without reading the rest.
+///
+/// With `--upload-byte-rate`, the body is also counted against the client's
+/// budget, and refused with `429 Too Many Requests` when it doesn't fit.
+async fn read_body_limited(req: &mut Request<State>, max: Option<u64>) -> tide::Result<Vec<u8>> {
+    let too_large = |max: u64| {
+        tide::Error::new(
+            StatusCode::PayloadTooLarge,
+            ImageError::BodyTooLarge { max },
+        )
+    };
+    if let (Some(max), Some(len)) = (max, req.len()) {
+        if len as u64 > max {
+            return Err(too_large(max));
+        }
+    }
+    let reservation = reserve_upload_bytes(req, max)?;
+    // A body without a length may not go past what was reserved for it.
+    let max = match (max, &reservation) {
+        (_, None) => max,
+        (None, Some((_, _, reserved))) => Some(*reserved),
+        (Some(max), Some((_, _, reserved))) => Some(max.min(*reserved)),
+    };
+    let limit = Duration::from_secs(req.state().config.body_read_timeout);
+    let body = req.take_body();
+    let mut bytes = Vec::new();
+    let read = async {
+        match max {
+            // One byte over is enough to tell the body is too long.
+            Some(max) => body.take(max + 1).read_to_end(&mut bytes).await?,
+            None => body.into_reader().read_to_end(&mut bytes).await?,
+        };
+        match max {
+            Some(max) if bytes.len() as u64 > max => Err(too_large(max)),
+            _ => Ok(()),
+        }
+    };
+    let result = async_std::future::timeout(limit, read).await;
+    // Whatever was read counts, even when the read failed.
+    if let Some((limiter, ip, reserved)) = reservation {
+        limiter.settle(ip, reserved, bytes.len() as u64);
+    }
+    match result {
+        Ok(Ok(())) => Ok(bytes),
+        Ok(Err(e)) => Err(e),
+        Err(_) => {
+            log::warn!("Timed out reading an upload body after {:?}", limit);
+            Err(tide::Error::from_str(
+                StatusCode::RequestTimeout,
+                "timed out reading the request body",
+            ))
+        }
+    }
+}
+
+/// With `--upload-byte-rate`, reserves room in the client's budget for the
+/// body about to be read: its declared length or, without one, `max` or the
+/// whole budget. Returns what to settle once the body is read.
+fn reserve_upload_bytes(
+    req: &Request<State>,
+    max: Option<u64>,
+) -> tide::Result<Option<(Arc<ByteRateLimiter>, IpAddr, u64)>> {
+    let (limiter, ip) = match (&req.state().upload_bytes, client_ip(req)) {
+        (Some(limiter), Some(ip)) => (Arc::clone(limiter), ip),
+        _ => return Ok(None),
+    };
+    let budget = limiter.budget();
+    let reserved = match req.len() {
+        Some(len) if len as u64 > budget => {
+            return Err(tide::Error::new(
+                StatusCode::PayloadTooLarge,
+                ImageError::BodyTooLarge { max: budget },
+            ))
+        }
+        Some(len) => len as u64,
+        None => max.map_or(budget, |max| max.min(budget)),
+    };
+    if let Err(wait) = limiter.reserve(ip, reserved) {
+        return Err(tide::Error::new(
+            StatusCode::TooManyRequests,
+            ImageError::TooManyBytes {
+                budget,
+                window: req.state().config.upload_byte_window,
+                retry: wait.as_secs() + 1,
+            },
+        ));
+    }
+    Ok(Some((limiter, ip, reserved)))
+}
+
+/// Address of the client at the other end of the connection. Proxy headers
+/// are ignored, since any client could set them.
+fn client_ip(req: &Request<State>) -> Option<IpAddr> {
+    req.peer_addr()?
+        .parse::<SocketAddr>()
+        .ok()
+        .map(|addr| addr.ip())
+}
+
+/// Rejects images whose pixel count exceeds the configured maximum, or the
+/// most that may be decoded and downscaled when oversize images are.
+fn check_pixels(state: &State, width: u32, height: u32) -> tide::Result<()> {
+    let max = match state.config.oversize_policy {
+        OversizePolicy::Reject => state.config.max_pixels,
+        OversizePolicy::Downscale => state
+            .config
+            .max_pixels
+            .saturating_mul(OVERSIZE_DECODE_FACTOR),
+    };
+    if width as u64 * height as u64 > max {
+        return Err(tide::Error::new(
+            StatusCode::PayloadTooLarge,
+            ImageError::TooManyPixels { width, height, max },
+        ));
+    }
+    Ok(())
+}
+
+/// Dimensions `fit_pixels` gives a `width`x`height` image.
+fn fitted_dimensions(state: &State, width: u32, height: u32) -> (u32, u32) {
+    let max = state.config.max_pixels;
+    let pixels = width as u64 * height as u64;
+    if pixels <= max {
+        return (width, height);
+    }
+    let scale = (max as f64 / pixels as f64).sqrt();
+    (
+        ((width as f64 * scale) as u32).max(1),
+        ((height as f64 * scale) as u32).max(1),
+    )
+}
+
+/// Shrinks a decoded image that is over the pixel limit to fit within it,
+/// keeping its aspect ratio. Only lets anything through with
+/// `--oversize-policy downscale`; otherwise `check_pixels` already refused it.
+fn fit_pixels(state: &State, img: DynamicImage) -> DynamicImage {
+    let (width, height) = fitted_dimensions(state, img.width(), img.height());
+    if (width, height) == (img.width(), img.height()) {
+        return img;
+    }
+    log::debug!(
+        "Downscaling {}x{} upload to {}x{}",
+        img.width(),
+        img.height(),
+        width,
+        height
+    );
+    img.resize_exact(width, height, image::imageops::FilterType::Triangle)
+}
+
+async fn transform_image(req: Request<State>) -> tide::Result {
+    let transforms: Transforms = req.query()?;
+    let (id, extension) = image_name(&req)?;
+    let contents = match req
+        .state()
+        .images
+        .read(id, extension, |img| match img.status {
+            ImageStatus::Pending => None,
+            ImageStatus::Ready => Some(img.contents.clone()),
+        })
+        .await
+    {
+        Some(Some(contents)) => contents,
+        Some(None) => {
+            return Err(tide::Error::from_str(
+                StatusCode::Conflict,
+                "image is still being crushed, try again later",
+            ))
+        }
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+    let mut img = transforms
+        .apply(image::load_from_memory(&contents[..])?)
+        .map_err(|e| tide::Error::new(StatusCode::BadRequest, e))?;
+    let mut passes = None;
+    let mut comment = None;
+    let mut quality = TRANSFORM_QUALITY;
+    if transforms.crush {
+        let options = req.state().crush_options();
+        let (crushed, report) = req.state().crush(img, &options, false)?;
+        img = crushed;
+        passes = Some(report.passes);
+        quality = JPEG_QUALITY;
+        comment = req.state().crush_comment(&options, &report, quality, None);
+    }
+    let src = store_image(req.state(), &img, quality, comment.as_deref()).await?;
+    upload_response(UploadResponse {
+        src: &src,
+        passes,
+        ..Default::default()
+    })
+}
+
+fn encode_jpeg(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, image::ImageError> {
+    let mut output: Vec<u8> = Default::default();
+    let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut output, quality);
+    encoder.encode_image(img)?;
+    Ok(output)
+}
+
+/// Highest JPEG quality at which `img`, with the `comment` it would get at
+/// that quality, encodes to at most `max_bytes`, or 1 when even that is too
+/// big. Binary search over the quality range, which `TARGET_SIZE_STEPS`
+/// steps cover.
+fn quality_for_size(
+    img: &DynamicImage,
+    max_bytes: u64,
+    comment: impl Fn(u8) -> Option<String>,
+) -> Result<u8, image::ImageError> {
+    let (mut low, mut high) = (1u8, 100u8);
+    let mut best = 1;
+    for _ in 0..TARGET_SIZE_STEPS {
+        if low > high {
+            break;
+        }
+        let mid = low + (high - low) / 2;
+        let mut jpeg = encode_jpeg(img, mid)?;
+        if let Some(comment) = comment(mid) {
+            insert_jpeg_comment(&mut jpeg, &comment);
+        }
+        if jpeg.len() as u64 <= max_bytes {
+            best = mid;
+            low = mid + 1;
+        } else {
+            high = mid - 1;
+        }
+    }
+    Ok(best)
+}
+
+/// Inserts a COM (comment) segment into `jpeg`, after the JFIF header which
+/// has to come first. Comments longer than a segment can hold are truncated.
+fn insert_jpeg_comment(jpeg: &mut Vec<u8>, comment: &str) {
+    let text = &comment.as_bytes()[..comment.len().min(u16::MAX as usize - 2)];
+    let mut segment = vec![0xFF, 0xFE];
+    segment.extend_from_slice(&(text.len() as u16 + 2).to_be_bytes());
+    segment.extend_from_slice(text);
+    // Right after SOI, or after the APP0 segment that follows it.
+    let at = match jpeg.get(2..6) {
+        Some([0xFF, 0xE0, hi, lo]) => 4 + u16::from_be_bytes([*hi, *lo]) as usize,
+        _ => 2,
+    };
+    jpeg.splice(at..at, segment);
+}
+
+/// The crush comment `insert_jpeg_comment` put in `jpeg`, if any.
+fn jpeg_comment(jpeg: &[u8]) -> Option<&str> {
+    let mut at = 2;
+    // Segments up to the start of the scan, each with its length after the
+    // marker.
+    while let Some(&[0xFF, marker, hi, lo]) = jpeg.get(at..at + 4) {
+        let end = at + 2 + u16::from_be_bytes([hi, lo]) as usize;
+        match marker {
+            0xDA => break,
+            0xFE => {
+                let text = std::str::from_utf8(jpeg.get(at + 4..end)?).ok()?;
+                return text.starts_with("more-jpeg ").then_some(text);
+            }
+            _ => at = end,
+        }
+    }
+    None
+}
+
+/// Encodes `img` as a JPEG (plus its thumbnail, if enabled), stores it under
+/// a new id and returns its `src`.
+async fn store_image(
+    state: &State,
+    img: &DynamicImage,
+    quality: u8,
+    comment: Option<&str>,
+) -> Result<String, image::ImageError> {
+    let img = encode_image(state, img, quality, comment)?;
+    Ok(insert_image(state, img).await)
+}
+
+/// Stores an already encoded image under a new id and returns its `src`.
+async fn insert_image(state: &State, img: Image) -> String {
+    let id = Ulid::new();
+    let src = state.image_src(&id.to_string(), img.format);
+
+    log::info!("src: {}", &src);
+
+    state.images.insert(id.to_string(), img).await;
+    state.counters.record_upload();
+    src
+}
+
+/// `data:` URI holding `contents`.
+fn data_uri(format: OutputFormat, contents: &[u8]) -> String {
+    format!("data:{};base64,{}", format.mime(), base64::encode(contents))
+}
+
+/// Encodes `img` into a ready-to-serve stored image, with `comment` in a
+/// JPEG COM segment of the full-size copy.
+fn encode_image(
+    state: &State,
+    img: &DynamicImage,
+    quality: u8,
+    comment: Option<&str>,
+) -> Result<Image, image::ImageError> {
+    let mut contents = encode_jpeg(img, quality)?;
+    if let Some(comment) = comment {
+        insert_jpeg_comment(&mut contents, comment);
+    }
+    let thumbnail = match state.config.thumbnail_size {
+        Some(size) => Some(encode_jpeg(&img.thumbnail(size, size), quality)?),
+        None => None,
+    };
+    let lqip = if state.config.lqip {
+        let preview = img.thumbnail(LQIP_SIZE, LQIP_SIZE).blur(LQIP_BLUR);
+        Some(data_uri(
+            OutputFormat::Jpeg,
+            &encode_jpeg(&preview, LQIP_QUALITY)?,
+        ))
+    } else {
+        None
+    };
+    Ok(Image {
+        status: ImageStatus::Ready,
+        format: OutputFormat::Jpeg,
+        contents,
+        thumbnail,
+        lqip,
+        save_data: None,
+        og: None,
+        palettes: HashMap::new(),
+        generation: 0,
+    })
+}
+
+/// Lists stored images in upload order.
+///
+/// Ids are ULIDs, whose string form sorts by creation time, so ordering
+/// the ids is enough and images don't need a separate timestamp. Should
+/// another id scheme ever be used, those ids would have to be ordered by a
+/// stored timestamp instead.
+async fn list_images(req: Request<State>) -> tide::Result {
+    let ListQuery { order } = req.query()?;
+    let state = req.state();
+    let mut images = state
+        .images
+        .list(|id, img| ListEntry {
+            id: id.to_string(),
+            src: state.image_src(id, img.format),
+        })
+        .await;
+    images.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+    if let ListOrder::Newest = order {
+        images.reverse();
+    }
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type(tide::http::mime::JSON);
+    res.set_body(tide::Body::from_json(&ListResponse { images })?);
+    Ok(res)
+}
+
+async fn collect_stats(state: &State) -> StatsResponse {
+    let images = state
+        .images
+        .list(|_, img| (img.status, img.contents.len()))
+        .await;
+    StatsResponse {
+        images: images.len(),
+        pending: images
+            .iter()
+            .filter(|(status, _)| *status == ImageStatus::Pending)
+            .count(),
+        bytes: images.iter().map(|(_, bytes)| bytes).sum(),
+        uploads: state.counters.uploads(),
+        evictions: state.counters.evictions(),
+        decode_failures: state.counters.decode_failures(),
+        crush_memory: CrushMemoryStats {
+            used: state.crush_memory.used(),
+            max: state.crush_memory.max(),
+        },
+    }
+}
+
+/// Logs a summary of `/stats` every `interval`.
+async fn log_stats(state: State, interval: Duration) {
+    let (mut uploads, mut evictions) = (0, 0);
+    loop {
+        async_std::task::sleep(interval).await;
+        let stats = collect_stats(&state).await;
+        log::info!(
+            "Stats: {} images ({} pending, {} bytes), {} uploads and {} evictions since last log",
+            stats.images,
+            stats.pending,
+            stats.bytes,
+            stats.uploads - uploads,
+            stats.evictions - evictions,
+        );
+        (uploads, evictions) = (stats.uploads, stats.evictions);
+    }
+}
+
+/// Starts re-encoding every stored image at a new quality in the background.
+/// Progress is at `GET /admin/reencode`.
+async fn start_reencode(req: Request<State>) -> tide::Result {
+    let state = req.state();
+    admin::authorize(&req, state.config.admin_key.as_deref())?;
+    let ReencodeQuery { quality } = req.query()?;
+    let quality = output_quality(&req, Some(quality))?;
+
+    let ids = state
+        .images
+        .list(|id, img| (img.status == ImageStatus::Ready).then(|| id.to_string()))
+        .await
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    if !state.reencode.start(quality, ids.len() as u64) {
+        return Err(tide::Error::from_str(
+            StatusCode::Conflict,
+            "a re-encode is already running",
+        ));
+    }
+    log::info!("Re-encoding {} images at quality {}", ids.len(), quality);
+    async_std::task::spawn(reencode_all(state.clone(), ids, quality));
+
+    let mut res = Response::new(StatusCode::Accepted);
+    res.set_content_type(tide::http::mime::JSON);
+    res.set_body(tide::Body::from_json(&state.reencode.status())?);
+    Ok(res)
+}
+
+async fn reencode_status(req: Request<State>) -> tide::Result {
+    admin::authorize(&req, req.state().config.admin_key.as_deref())?;
+    let mut res = Response::new(StatusCode::Ok);
+    res.insert_header("Cache-Control", "no-store");
+    res.set_content_type(tide::http::mime::JSON);
+    res.set_body(tide::Body::from_json(&req.state().reencode.status())?);
+    Ok(res)
+}
+
+/// Gives a client (`?ip=`), or every client (`?ip=all`), its whole
+/// `--upload-byte-rate` budget back, e.g. after a false positive.
+async fn reset_limits(req: Request<State>) -> tide::Result {
+    let state = req.state();
+    admin::authorize(&req, state.config.admin_key.as_deref())?;
+    let ResetLimitsQuery { ip } = req.query()?;
+    let client = match ip.as_str() {
+        "all" => None,
+        ip => Some(ip.parse::<IpAddr>().map_err(|_| {
+            tide::Error::from_str(
+                StatusCode::BadRequest,
+                format!("invalid ip {:?}, expected an address or \"all\"", ip),
+            )
+        })?),
+    };
+    let reset = state
+        .upload_bytes
+        .as_ref()
+        .map_or(0, |limiter| limiter.reset(client));
+    log::info!("Reset the upload limits of {} ({} client(s))", ip, reset);
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type(tide::http::mime::JSON);
+    res.set_body(tide::Body::from_json(&ResetLimitsResponse {
+        ip: &ip,
+        reset,
+    })?);
+    Ok(res)
+}
+
+/// Re-encodes the given images one by one, off the request path. Images
+/// deleted or replaced in the meantime are skipped, and so are those the
+/// new quality doesn't make smaller. The crush comment, if any, is carried
+/// over with the quality of the re-encode added.
+async fn reencode_all(state: State, ids: Vec<String>, quality: u8) {
+    for id in ids {
+        let stored = state
+            .images
+            .read(&id, None, |img| {
+                (img.status == ImageStatus::Ready).then(|| img.clone())
+            })
+            .await
+            .flatten();
+        let stored = match stored {
+            Some(stored) => stored,
+            None => continue,
+        };
+        // The stored image keeps being served as is until the new one is
+        // complete and swapped in.
+        let reencoded = async_std::task::spawn_blocking(move || -> tide::Result<Option<Image>> {
+            let mut contents =
+                encode_jpeg(&image::load_from_memory(&stored.contents[..])?, quality)?;
+            if let Some(comment) = jpeg_comment(&stored.contents) {
+                let comment = format!("{} reencode_quality={}", comment, quality);
+                insert_jpeg_comment(&mut contents, &comment);
+            }
+            if contents.len() >= stored.contents.len() {
+                return Ok(None);
+            }
+            Ok(Some(Image {
+                contents,
+                // Derived from the old bytes.
+                save_data: None,
+                og: None,
+                palettes: HashMap::new(),
+                ..stored
+            }))
+        })
+        .await;
+        match reencoded {
+            Ok(Some(reencoded)) => {
+                state.images.replace(&id, reencoded).await;
+                state.reencode.record(ReencodeOutcome::Replaced);
+            }
+            Ok(None) => state.reencode.record(ReencodeOutcome::Kept),
+            Err(e) => {
+                log::warn!("Could not re-encode {}: {}", id, e);
+                state.reencode.record(ReencodeOutcome::Failed);
+            }
+        }
+    }
+    let status = state.reencode.status();
+    let summary = format!(
+        "re-encoded {} of {} images at quality {}, {} weren't smaller, {} failed",
+        status.done, status.total, quality, status.kept, status.failed
+    );
+    log::info!("Finished re-encoding: {}", summary);
+    state.reencode.finish(summary);
+}
+
+/// Current state of the server, for monitoring.
+async fn serve_stats(req: Request<State>) -> tide::Result {
+    let stats = collect_stats(req.state()).await;
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.insert_header("Cache-Control", "no-store");
+    res.set_content_type(tide::http::mime::JSON);
+    res.set_body(tide::Body::from_json(&stats)?);
+    Ok(res)
+}
+
+/// Uploads and crush latency per minute over the last hour, oldest first.
+async fn serve_timeseries(req: Request<State>) -> tide::Result {
+    let body = TimeseriesResponse {
+        bucket_seconds: stats::BUCKET.as_secs(),
+        buckets: req.state().counters.timeseries(),
+    };
+    let mut res = Response::new(StatusCode::Ok);
+    res.insert_header("Cache-Control", "no-store");
+    res.set_content_type(tide::http::mime::JSON);
+    res.set_body(tide::Body::from_json(&body)?);
+    Ok(res)
+}
+
+fn upload_response(body: UploadResponse) -> tide::Result {
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type(tide::http::mime::JSON);
+    res.set_body(tide::Body::from_json(&body)?);
+    Ok(res)
+}
+
+/// Splits the `:name` route parameter into the image id and its extension,
+/// if it has one.
+fn image_name(req: &Request<State>) -> Result<(&str, Option<&str>), ImageError> {
+    let name = req.param("name").map_err(|_| ImageError::InvalidId)?;
+    Ok(match name.split_once('.') {
+        Some((id, extension)) => (id, Some(extension)),
+        None => (name, None),
+    })
+}
+
 async fn serve_image(req: Request<State>) -> Result<Response, Box<dyn Error>> {
-    let id = req.param("name").map_err(|_| ImageError::InvalidId)?;
-    let id = id.split('.').rev().last().unwrap();
-    let rw = req.state().images.clone();
-    let images = rw.read().await;
-    if let Some(img) = images.get(id) {
-        log::debug!("Found valid id: {}", id);
-        let mut res = Response::new(200);
-        res.set_content_type(img.mime.clone());
-        res.set_body(&img.contents[..]);
-        Ok(res)
+    let (id, extension) = image_name(&req)?;
+    let save_data = req
+        .header("Save-Data")
+        .map(|values| values.as_str().trim().eq_ignore_ascii_case("on"))
+        .unwrap_or(false);
+
+    let images = &req.state().images;
+    let found = images
+        .read(id, extension, |img| {
+            match (img.status, &img.save_data, save_data) {
+                (ImageStatus::Pending, _, _) => None,
+                (ImageStatus::Ready, Some(reduced), true) => {
+                    Some((img.format, reduced.clone(), true, img.generation))
+                }
+                (ImageStatus::Ready, _, _) => {
+                    Some((img.format, img.contents.clone(), false, img.generation))
+                }
+            }
+        })
+        .await;
+    let (format, contents, reduced, generation) = match found {
+        Some(Some(found)) => {
+            log::debug!("Found valid id: {}", id);
+            found
+        }
+        Some(None) => return Ok(pending_response(req.state())),
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+
+    let mime = format.mime();
+    let allowed = &req.state().config.serve_mime_types;
+    if !allowed.iter().any(|allowed| allowed == mime.essence()) {
+        log::warn!("Refusing to serve {}, stored as {}", id, mime.essence());
+        return Ok(Response::new(StatusCode::UnsupportedMediaType));
+    }
+
+    let contents = if save_data && !reduced {
+        let reduced = encode_jpeg(&image::load_from_memory(&contents[..])?, SAVE_DATA_QUALITY)?;
+        // Images crushed at a low quality already can come out bigger.
+        let reduced = if reduced.len() < contents.len() {
+            reduced
+        } else {
+            contents
+        };
+        images
+            .update(id, generation, |img| img.save_data = Some(reduced.clone()))
+            .await;
+        reduced
     } else {
-        Ok(Response::new(StatusCode::NotFound))
+        contents
+    };
+
+    let mut res = Response::new(200);
+    res.insert_header("Vary", "Save-Data");
+    res.set_content_type(mime);
+    res.set_body(contents);
+    Ok(res)
+}
+
+/// Placeholder answer for an image that exists but isn't crushed yet.
+fn pending_response(state: &State) -> Response {
+    let mut res = Response::new(StatusCode::Ok);
+    res.insert_header("Retry-After", PENDING_RETRY_AFTER.to_string());
+    res.insert_header("Cache-Control", "no-store");
+    res.set_content_type(tide::http::mime::JPEG);
+    res.set_body(&state.placeholder[..]);
+    res
+}
+
+/// Describes a stored image without sending it.
+async fn serve_meta(req: Request<State>) -> tide::Result {
+    let (id, extension) = image_name(&req)?;
+    let state = req.state();
+    let meta = state
+        .images
+        .read(id, extension, |img| MetaResponse {
+            id,
+            src: state.image_src(id, img.format),
+            status: match img.status {
+                ImageStatus::Pending => "pending",
+                ImageStatus::Ready => "ready",
+            },
+            bytes: match img.status {
+                ImageStatus::Pending => 0,
+                ImageStatus::Ready => img.contents.len(),
+            },
+            lqip: img.lqip.clone(),
+        })
+        .await;
+    let meta = match meta {
+        Some(meta) => meta,
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type(tide::http::mime::JSON);
+    res.set_body(tide::Body::from_json(&meta)?);
+    Ok(res)
+}
+
+async fn serve_thumbnail(req: Request<State>) -> Result<Response, Box<dyn Error>> {
+    let (id, extension) = image_name(&req)?;
+    let thumbnail = req
+        .state()
+        .images
+        .read(id, extension, |img| (img.status, img.thumbnail.clone()))
+        .await;
+    match thumbnail {
+        Some((ImageStatus::Pending, _)) => Ok(pending_response(req.state())),
+        Some((ImageStatus::Ready, Some(thumbnail))) => {
+            let mut res = Response::new(StatusCode::Ok);
+            res.set_content_type(tide::http::mime::JPEG);
+            res.set_body(thumbnail);
+            Ok(res)
+        }
+        Some((ImageStatus::Ready, None)) | None => Ok(Response::new(StatusCode::NotFound)),
     }
 }
+
+/// The image cropped to the Open Graph size, for link previews. Crops are
+/// cached on the image.
+async fn serve_og(req: Request<State>) -> Result<Response, Box<dyn Error>> {
+    let (id, extension) = image_name(&req)?;
+    let images = &req.state().images;
+    let found = images
+        .read(id, extension, |img| match img.status {
+            ImageStatus::Pending => None,
+            ImageStatus::Ready => Some(match &img.og {
+                Some(og) => Ok(og.clone()),
+                None => Err((img.generation, img.contents.clone())),
+            }),
+        })
+        .await;
+    let og = match found {
+        Some(Some(Ok(og))) => og,
+        Some(Some(Err((generation, contents)))) => {
+            let img = image::load_from_memory(&contents[..])?;
+            let og = encode_jpeg(&transform::og_crop(&img), OG_QUALITY)?;
+            images
+                .update(id, generation, |img| img.og = Some(og.clone()))
+                .await;
+            og
+        }
+        Some(None) => return Ok(pending_response(req.state())),
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type(tide::http::mime::JPEG);
+    res.set_body(og);
+    Ok(res)
+}
+
+/// Dominant colors of a stored image as a JSON array of `#rrggbb` strings,
+/// most common first. Palettes are cached on the image per color count.
+async fn serve_palette(req: Request<State>) -> tide::Result {
+    let PaletteQuery { n } = req.query()?;
+    let n = n
+        .unwrap_or(palette::DEFAULT_COLORS)
+        .clamp(1, palette::MAX_COLORS);
+    let (id, extension) = image_name(&req)?;
+    let images = &req.state().images;
+    let found = images
+        .read(id, extension, |img| match img.status {
+            ImageStatus::Pending => None,
+            ImageStatus::Ready => Some(match img.palettes.get(&n) {
+                Some(colors) => Ok(colors.clone()),
+                None => Err((img.generation, img.contents.clone())),
+            }),
+        })
+        .await;
+    let colors = match found {
+        Some(Some(Ok(colors))) => colors,
+        Some(Some(Err((generation, contents)))) => {
+            let img = image::load_from_memory(&contents[..])?;
+            let colors: Vec<String> = palette::dominant_colors(&img, n)
+                .into_iter()
+                .map(palette::hex)
+                .collect();
+            images
+                .update(id, generation, |img| img.palettes.insert(n, colors.clone()))
+                .await;
+            colors
+        }
+        Some(None) => {
+            return Err(tide::Error::from_str(
+                StatusCode::Conflict,
+                "image is still being crushed, try again later",
+            ))
+        }
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type(tide::http::mime::JSON);
+    res.set_body(tide::Body::from_json(&colors)?);
+    Ok(res)
+}
@@ -0,0 +1,63 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// Accounts for the memory held by crushes in flight, so their total can be
+/// capped.
+#[derive(Debug)]
+pub struct CrushMemory {
+    used: AtomicU64,
+    /// Most bytes all crushes together may hold, if capped.
+    max: Option<u64>,
+}
+
+/// Memory granted to one crush, given back when dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    memory: Arc<CrushMemory>,
+    bytes: u64,
+}
+
+impl CrushMemory {
+    pub fn new(max: Option<u64>) -> Self {
+        Self {
+            used: AtomicU64::new(0),
+            max,
+        }
+    }
+
+    /// Bytes currently reserved.
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn max(&self) -> Option<u64> {
+        self.max
+    }
+
+    /// Whether a crush needing `bytes` could ever be granted.
+    pub fn fits(&self, bytes: u64) -> bool {
+        self.max.is_none_or(|max| bytes <= max)
+    }
+
+    /// Reserves `bytes`, unless that would take the total over the cap.
+    pub fn try_reserve(self: &Arc<Self>, bytes: u64) -> Option<Reservation> {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                let total = used.checked_add(bytes)?;
+                self.max.is_none_or(|max| total <= max).then_some(total)
+            })
+            .ok()?;
+        Some(Reservation {
+            memory: Arc::clone(self),
+            bytes,
+        })
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.memory.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
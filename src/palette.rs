@@ -0,0 +1,78 @@
+use image::{DynamicImage, Rgb};
+
+/// Colors returned when the request doesn't ask for a count.
+pub const DEFAULT_COLORS: usize = 5;
+/// Most colors a palette can have.
+pub const MAX_COLORS: usize = 16;
+/// Images are shrunk to fit this square before sampling; dominant colors
+/// survive the downscale and it bounds the work per request.
+const SAMPLE_SIZE: u32 = 128;
+
+/// Finds up to `n` dominant colors of `img` by median cut, most common first.
+///
+/// Fewer than `n` colors come back when the image doesn't have that many
+/// distinct ones.
+pub fn dominant_colors(img: &DynamicImage, n: usize) -> Vec<Rgb<u8>> {
+    let pixels: Vec<[u8; 3]> = img
+        .thumbnail(SAMPLE_SIZE, SAMPLE_SIZE)
+        .to_rgb8()
+        .pixels()
+        .map(|p| p.0)
+        .collect();
+    if pixels.is_empty() {
+        return Vec::new();
+    }
+
+    let mut boxes = vec![pixels];
+    while boxes.len() < n {
+        // Split the box with the widest channel range; stop once every box
+        // holds a single color.
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .map(|(i, b)| (i, widest_channel(b)))
+            .filter(|(_, (_, range))| *range > 0)
+            .max_by_key(|(_, (_, range))| *range);
+        let Some((index, (channel, _))) = widest else {
+            break;
+        };
+        let mut pixels = boxes.swap_remove(index);
+        pixels.sort_unstable_by_key(|p| p[channel]);
+        let upper = pixels.split_off(pixels.len() / 2);
+        boxes.push(pixels);
+        boxes.push(upper);
+    }
+
+    boxes.sort_unstable_by_key(|b| std::cmp::Reverse(b.len()));
+    boxes.iter().map(|b| average(b)).collect()
+}
+
+/// Formats a color as `#rrggbb`.
+pub fn hex(color: Rgb<u8>) -> String {
+    let [r, g, b] = color.0;
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// The channel with the largest spread in `pixels`, and that spread.
+fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+    (0..3)
+        .map(|c| {
+            let (min, max) = pixels.iter().fold((u8::MAX, u8::MIN), |(min, max), p| {
+                (min.min(p[c]), max.max(p[c]))
+            });
+            (c, max - min)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap_or((0, 0))
+}
+
+fn average(pixels: &[[u8; 3]]) -> Rgb<u8> {
+    let mut sums = [0u64; 3];
+    for p in pixels {
+        for (sum, &v) in sums.iter_mut().zip(p) {
+            *sum += v as u64;
+        }
+    }
+    let len = pixels.len() as u64;
+    Rgb(sums.map(|sum| ((sum + len / 2) / len) as u8))
+}
@@ -0,0 +1,82 @@
+use crate::{
+    crush::{BitCrush, CrushOptions},
+    palette,
+};
+use image::{imageops::FilterType, DynamicImage, Rgb, RgbImage};
+use serde::Serialize;
+use std::io::Cursor;
+
+/// Sizes of the generated app icons, the two every platform asks for.
+pub const ICON_SIZES: [u32; 2] = [192, 512];
+/// Side of the tiny gradient the icons are crushed from before being
+/// blown up, so the JPEG blocks stay big and crisp.
+const ICON_SOURCE_SIZE: u32 = 24;
+
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    pub name: String,
+    pub short_name: String,
+    pub start_url: String,
+    pub scope: String,
+    pub display: &'static str,
+    pub background_color: String,
+    pub theme_color: String,
+    pub icons: Vec<ManifestIcon>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ManifestIcon {
+    pub src: String,
+    pub sizes: String,
+    #[serde(rename = "type")]
+    pub mime: &'static str,
+}
+
+impl Manifest {
+    /// Web app manifest for an app mounted at `base_path`.
+    pub fn new(name: &str, theme: Rgb<u8>, base_path: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            short_name: name.to_string(),
+            start_url: format!("{}/", base_path),
+            scope: format!("{}/", base_path),
+            display: "standalone",
+            background_color: palette::hex(theme),
+            theme_color: palette::hex(theme),
+            icons: ICON_SIZES
+                .iter()
+                .map(|&size| ManifestIcon {
+                    src: format!("{}/icons/{}", base_path, icon_name(size)),
+                    sizes: format!("{}x{}", size, size),
+                    mime: "image/png",
+                })
+                .collect(),
+        }
+    }
+}
+
+/// File name an icon of this size is served under.
+pub fn icon_name(size: u32) -> String {
+    format!("icon-{}.png", size)
+}
+
+/// Renders a `size`x`size` PNG icon: a gradient from `theme` to white,
+/// crushed like any upload would be.
+pub fn render_icon(size: u32, theme: Rgb<u8>) -> Result<Vec<u8>, image::ImageError> {
+    let n = ICON_SOURCE_SIZE;
+    let source = RgbImage::from_fn(n, n, |x, y| {
+        let t = (x + y) as f32 / (2 * (n - 1)) as f32;
+        Rgb(theme.0.map(|c| (c as f32 + (255.0 - c as f32) * t) as u8))
+    });
+    let options = CrushOptions {
+        deterministic: true,
+        ..Default::default()
+    };
+    let (crushed, _) = DynamicImage::ImageRgb8(source).bitcrush(&options)?;
+
+    let mut png = Vec::new();
+    crushed
+        .resize_exact(size, size, FilterType::Nearest)
+        .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)?;
+    Ok(png)
+}
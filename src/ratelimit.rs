@@ -0,0 +1,94 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Per-client token buckets of upload bytes: every client may send up to
+/// `budget` bytes at once, refilled at `budget` bytes per `window`.
+#[derive(Debug)]
+pub struct ByteRateLimiter {
+    budget: u64,
+    window: Duration,
+    clients: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes the client may still send, with what's reserved for bodies
+    /// being read already taken off.
+    available: f64,
+    updated: Instant,
+}
+
+impl ByteRateLimiter {
+    pub fn new(budget: u64, window: Duration) -> Self {
+        Self {
+            budget,
+            window,
+            clients: Default::default(),
+        }
+    }
+
+    pub fn budget(&self) -> u64 {
+        self.budget
+    }
+
+    /// Takes `bytes` off what `ip` may send, before its body is read, or
+    /// returns how long until it has that much. A body longer than the whole
+    /// budget never fits; callers should check that first.
+    pub fn reserve(&self, ip: IpAddr, bytes: u64) -> Result<(), Duration> {
+        let mut clients = self.clients.lock().unwrap();
+        let bucket = self.refill(&mut clients, ip);
+        let missing = bytes as f64 - bucket.available;
+        if missing > 0.0 {
+            return Err(self.window.mul_f64(missing / self.budget as f64));
+        }
+        bucket.available -= bytes as f64;
+        Ok(())
+    }
+
+    /// Gives back what a read reserved but didn't use, once it's over,
+    /// whether it succeeded or not.
+    pub fn settle(&self, ip: IpAddr, reserved: u64, used: u64) {
+        let mut clients = self.clients.lock().unwrap();
+        self.refill(&mut clients, ip).available += reserved as f64 - used as f64;
+    }
+
+    /// Gives `ip`, or every client, its whole budget back, returning how
+    /// many clients that was.
+    pub fn reset(&self, ip: Option<IpAddr>) -> usize {
+        let mut clients = self.clients.lock().unwrap();
+        match ip {
+            Some(ip) => clients.remove(&ip).map_or(0, |_| 1),
+            None => std::mem::take(&mut *clients).len(),
+        }
+    }
+
+    /// Forgets clients whose budget is whole again, returning how many.
+    pub fn expire(&self) -> usize {
+        let mut clients = self.clients.lock().unwrap();
+        let before = clients.len();
+        let (budget, window) = (self.budget as f64, self.window);
+        clients.retain(|_, bucket| {
+            let refilled = bucket.updated.elapsed().as_secs_f64() / window.as_secs_f64() * budget;
+            bucket.available + refilled < budget
+        });
+        before - clients.len()
+    }
+
+    fn refill<'a>(&self, clients: &'a mut HashMap<IpAddr, Bucket>, ip: IpAddr) -> &'a mut Bucket {
+        let now = Instant::now();
+        let bucket = clients.entry(ip).or_insert(Bucket {
+            available: self.budget as f64,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.available = (bucket.available
+            + elapsed / self.window.as_secs_f64() * self.budget as f64)
+            .min(self.budget as f64);
+        bucket.updated = now;
+        bucket
+    }
+}
@@ -0,0 +1,30 @@
+use futures_lite::FutureExt;
+use std::panic::AssertUnwindSafe;
+use tide::{utils::async_trait, Middleware, Next, Request, Response, StatusCode};
+
+/// Turns a panicking handler into a `503 Service Unavailable` instead of a
+/// dropped connection.
+///
+/// Any lock guard the handler held is released while unwinding, so the
+/// next request finds the shared state usable again.
+#[derive(Debug, Default)]
+pub struct CatchPanic;
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for CatchPanic {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let path = req.url().path().to_string();
+        match AssertUnwindSafe(next.run(req)).catch_unwind().await {
+            Ok(res) => Ok(res),
+            Err(panic) => {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                log::error!("Handler for {} panicked: {}", path, message);
+                Ok(Response::new(StatusCode::ServiceUnavailable))
+            }
+        }
+    }
+}
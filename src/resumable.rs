@@ -0,0 +1,100 @@
+use async_std::sync::RwLock;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use ulid::Ulid;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AppendError {
+    #[error("no such upload, it may have expired")]
+    NotFound,
+    #[error("chunk starts at offset {got}, but the upload has {expected} bytes")]
+    OffsetMismatch { expected: u64, got: u64 },
+    #[error("upload would be larger than {max} bytes")]
+    TooLarge { max: u64 },
+}
+
+#[derive(Debug)]
+struct PartialUpload {
+    data: Vec<u8>,
+    /// Last time a chunk arrived, or when the upload was started.
+    touched: Instant,
+}
+
+/// Uploads sent in chunks that haven't been finished yet.
+///
+/// Like `ImageStore`, lock guards never leave this type.
+#[derive(Debug, Default)]
+pub struct PartialUploads {
+    uploads: RwLock<HashMap<String, PartialUpload>>,
+}
+
+impl PartialUploads {
+    /// Starts an empty upload and returns its id.
+    pub async fn start(&self) -> String {
+        let uid = Ulid::new().to_string();
+        let mut uploads = self.uploads.write().await;
+        uploads.insert(
+            uid.clone(),
+            PartialUpload {
+                data: Vec::new(),
+                touched: Instant::now(),
+            },
+        );
+        uid
+    }
+
+    /// Number of bytes received so far.
+    pub async fn offset(&self, uid: &str) -> Option<u64> {
+        let uploads = self.uploads.read().await;
+        uploads.get(uid).map(|upload| upload.data.len() as u64)
+    }
+
+    /// Appends `chunk` if it starts where the upload currently ends and keeps
+    /// it within `max` bytes, returning the new offset.
+    pub async fn append(
+        &self,
+        uid: &str,
+        offset: u64,
+        chunk: &[u8],
+        max: u64,
+    ) -> Result<u64, AppendError> {
+        let mut uploads = self.uploads.write().await;
+        let upload = uploads.get_mut(uid).ok_or(AppendError::NotFound)?;
+        let expected = upload.data.len() as u64;
+        if offset != expected {
+            return Err(AppendError::OffsetMismatch {
+                expected,
+                got: offset,
+            });
+        }
+        if expected + chunk.len() as u64 > max {
+            return Err(AppendError::TooLarge { max });
+        }
+        upload.data.extend_from_slice(chunk);
+        upload.touched = Instant::now();
+        Ok(upload.data.len() as u64)
+    }
+
+    /// Everything received so far.
+    pub async fn get(&self, uid: &str) -> Option<Vec<u8>> {
+        let uploads = self.uploads.read().await;
+        uploads.get(uid).map(|upload| upload.data.clone())
+    }
+
+    /// Removes a finished upload.
+    pub async fn remove(&self, uid: &str) {
+        let mut uploads = self.uploads.write().await;
+        uploads.remove(uid);
+    }
+
+    /// Drops uploads that got no chunk for longer than `ttl`, returning how
+    /// many were dropped.
+    pub async fn expire(&self, ttl: Duration) -> usize {
+        let mut uploads = self.uploads.write().await;
+        let before = uploads.len();
+        uploads.retain(|_, upload| upload.touched.elapsed() <= ttl);
+        before - uploads.len()
+    }
+}
@@ -0,0 +1,133 @@
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Length of one bucket of the time series.
+pub const BUCKET: Duration = Duration::from_secs(60);
+/// Number of buckets kept, i.e. the last hour.
+pub const BUCKETS: usize = 60;
+
+/// Running totals since the server started, read by `/stats` and the
+/// periodic stats log.
+#[derive(Debug, Default)]
+pub struct Counters {
+    /// Images stored, crushed or pending.
+    uploads: AtomicU64,
+    /// Images dropped from the store without being asked to.
+    evictions: AtomicU64,
+    /// Uploads that couldn't be decoded, by sniffed format.
+    decode_failures: Mutex<BTreeMap<String, u64>>,
+    /// Per-minute activity over the last `BUCKETS` minutes, oldest first.
+    /// Minutes without activity have no bucket.
+    timeseries: Mutex<VecDeque<Bucket>>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Bucket {
+    /// Index of the bucket since the Unix epoch.
+    index: u64,
+    uploads: u64,
+    crushes: u64,
+    crush_time: Duration,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TimeseriesPoint {
+    /// Unix time, in seconds, the bucket starts at.
+    pub start: u64,
+    pub uploads: u64,
+    pub crushes: u64,
+    /// Average time a crush took, or `null` without any crush.
+    pub avg_crush_ms: Option<f64>,
+}
+
+impl Counters {
+    pub fn record_upload(&self) {
+        self.uploads.fetch_add(1, Ordering::Relaxed);
+        self.update_bucket(|bucket| bucket.uploads += 1);
+    }
+
+    pub fn record_crush(&self, elapsed: Duration) {
+        self.update_bucket(|bucket| {
+            bucket.crushes += 1;
+            bucket.crush_time += elapsed;
+        });
+    }
+
+    pub fn record_eviction(&self) {
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn uploads(&self) -> u64 {
+        self.uploads.load(Ordering::Relaxed)
+    }
+
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    /// Counts an upload of `format` (`"unknown"` when it couldn't even be
+    /// sniffed) that failed to decode.
+    pub fn record_decode_failure(&self, format: &str) {
+        let mut failures = self.decode_failures.lock().unwrap();
+        *failures.entry(format.to_string()).or_default() += 1;
+    }
+
+    pub fn decode_failures(&self) -> BTreeMap<String, u64> {
+        self.decode_failures.lock().unwrap().clone()
+    }
+
+    /// One point per bucket of the last `BUCKETS`, oldest first, including
+    /// the current, still filling one.
+    pub fn timeseries(&self) -> Vec<TimeseriesPoint> {
+        let now = current_bucket();
+        let buckets = self.timeseries.lock().unwrap();
+        (now + 1 - BUCKETS as u64..=now)
+            .map(|index| {
+                let bucket = buckets
+                    .iter()
+                    .find(|b| b.index == index)
+                    .cloned()
+                    .unwrap_or_default();
+                TimeseriesPoint {
+                    start: index * BUCKET.as_secs(),
+                    uploads: bucket.uploads,
+                    crushes: bucket.crushes,
+                    avg_crush_ms: (bucket.crushes > 0)
+                        .then(|| bucket.crush_time.as_secs_f64() * 1000.0 / bucket.crushes as f64),
+                }
+            })
+            .collect()
+    }
+
+    fn update_bucket(&self, f: impl FnOnce(&mut Bucket)) {
+        let index = current_bucket();
+        let mut buckets = self.timeseries.lock().unwrap();
+        if buckets.back().is_none_or(|b| b.index != index) {
+            buckets.push_back(Bucket {
+                index,
+                ..Default::default()
+            });
+        }
+        while buckets
+            .front()
+            .is_some_and(|b| b.index + (BUCKETS as u64) <= index)
+        {
+            buckets.pop_front();
+        }
+        f(buckets.back_mut().expect("a bucket was just pushed"));
+    }
+}
+
+fn current_bucket() -> u64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    since_epoch.as_secs() / BUCKET.as_secs()
+}
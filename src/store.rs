@@ -0,0 +1,111 @@
+use crate::format::OutputFormat;
+use async_std::sync::RwLock;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageStatus {
+    /// Still being crushed; `contents` holds the original upload.
+    Pending,
+    Ready,
+}
+
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub status: ImageStatus,
+    pub format: OutputFormat,
+    pub contents: Vec<u8>,
+    /// JPEG thumbnail, when thumbnails are enabled.
+    pub thumbnail: Option<Vec<u8>>,
+    /// Tiny blurred preview as a `data:` URI, when LQIPs are enabled.
+    pub lqip: Option<String>,
+    /// Lower quality copy for `Save-Data` clients, encoded on first request,
+    /// or the contents themselves when that copy wasn't any smaller.
+    pub save_data: Option<Vec<u8>>,
+    /// Open Graph sized crop, encoded on first request.
+    pub og: Option<Vec<u8>>,
+    /// Dominant colors already computed, as hex strings, by color count.
+    pub palettes: HashMap<usize, Vec<String>>,
+    /// Bumped by `ImageStore::replace`, so copies derived from `contents`
+    /// can tell the contents changed since they were read.
+    pub generation: u64,
+}
+
+/// In-memory image storage.
+///
+/// Lock guards never leave this type: callers get a closure over the entry
+/// instead, which can't `.await`, so a guard is only ever held for a short,
+/// synchronous section. async_std's `RwLock` doesn't poison, and a panic
+/// inside one of those closures drops the guard while unwinding, so a
+/// panicking handler can't wedge the store for everyone else.
+#[derive(Debug, Default)]
+pub struct ImageStore {
+    images: RwLock<HashMap<String, Image>>,
+}
+
+impl ImageStore {
+    /// Runs `f` on the image with this id, if there is one. An `extension`
+    /// that doesn't match the stored format counts as a missing image.
+    pub async fn read<R>(
+        &self,
+        id: &str,
+        extension: Option<&str>,
+        f: impl FnOnce(&Image) -> R,
+    ) -> Option<R> {
+        let images = self.images.read().await;
+        images
+            .get(id)
+            .filter(|img| extension.is_none_or(|ext| img.format.matches_extension(ext)))
+            .map(f)
+    }
+
+    /// Runs `f` on a mutable reference to the image with this id, if there
+    /// is one and it's still at `generation`. Anything derived from contents
+    /// read earlier is stored this way, so it can't end up attached to
+    /// contents that were replaced in the meantime.
+    pub async fn update<R>(
+        &self,
+        id: &str,
+        generation: u64,
+        f: impl FnOnce(&mut Image) -> R,
+    ) -> Option<R> {
+        let mut images = self.images.write().await;
+        images
+            .get_mut(id)
+            .filter(|img| img.generation == generation)
+            .map(f)
+    }
+
+    /// Swaps `image` in for the one stored under `id` in a single write, so
+    /// readers see either the old image or the new one, never a mix. Build
+    /// the new image before calling this, not under the lock. Returns
+    /// whether there was an image to replace; a removed one stays removed.
+    pub async fn replace(&self, id: &str, image: Image) -> bool {
+        let mut images = self.images.write().await;
+        match images.get_mut(id) {
+            Some(stored) => {
+                *stored = Image {
+                    generation: stored.generation + 1,
+                    ..image
+                };
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub async fn insert(&self, id: String, image: Image) {
+        let mut images = self.images.write().await;
+        images.insert(id, image);
+    }
+
+    pub async fn remove(&self, id: &str) -> Option<Image> {
+        let mut images = self.images.write().await;
+        images.remove(id)
+    }
+
+    /// Runs `f` on every stored image, in no particular order.
+    pub async fn list<R>(&self, mut f: impl FnMut(&str, &Image) -> R) -> Vec<R> {
+        let images = self.images.read().await;
+        images.iter().map(|(id, img)| f(id, img)).collect()
+    }
+}
@@ -0,0 +1,116 @@
+use image::{imageops::FilterType, DynamicImage, Rgb, RgbImage};
+use serde::Deserialize;
+
+/// Largest width or height a `resize` transform may ask for.
+pub const MAX_RESIZE_DIMENSION: u32 = 4096;
+
+/// Size of the images link previews show, per the Open Graph guidelines.
+pub const OG_WIDTH: u32 = 1200;
+pub const OG_HEIGHT: u32 = 630;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransformError {
+    #[error(
+        "invalid size {0:?}, expected WIDTHxHEIGHT (each 1..={})",
+        MAX_RESIZE_DIMENSION
+    )]
+    InvalidSize(String),
+    #[error("invalid color {0:?}, expected a hex color like ff8800")]
+    InvalidColor(String),
+}
+
+/// Standalone image operations, applied in declaration order.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Transforms {
+    /// Fit the image within `WIDTHxHEIGHT`, keeping its aspect ratio.
+    pub resize: Option<String>,
+    pub grayscale: bool,
+    pub invert: bool,
+    /// Multiply every pixel by this hex color.
+    pub tint: Option<String>,
+    /// Run a full bitcrush after the transforms.
+    pub crush: bool,
+}
+
+impl Transforms {
+    pub fn apply(&self, img: DynamicImage) -> Result<DynamicImage, TransformError> {
+        let mut img = img;
+        if let Some(size) = &self.resize {
+            let (w, h) = parse_size(size)?;
+            img = img.resize(w, h, FilterType::Triangle);
+        }
+        if self.grayscale {
+            img = img.grayscale();
+        }
+        if self.invert {
+            img.invert();
+        }
+        if let Some(tint) = &self.tint {
+            let Rgb([r, g, b]) = parse_color(tint)?;
+            let mut rgb = img.into_rgb8();
+            for pixel in rgb.pixels_mut() {
+                pixel[0] = (pixel[0] as u16 * r as u16 / 255) as u8;
+                pixel[1] = (pixel[1] as u16 * g as u16 / 255) as u8;
+                pixel[2] = (pixel[2] as u16 * b as u16 / 255) as u8;
+            }
+            img = DynamicImage::ImageRgb8(rgb);
+        }
+        Ok(img)
+    }
+}
+
+/// Scales `img` to cover `OG_WIDTH`x`OG_HEIGHT` and crops the overflow
+/// evenly from both sides.
+pub fn og_crop(img: &DynamicImage) -> DynamicImage {
+    img.resize_to_fill(OG_WIDTH, OG_HEIGHT, FilterType::Triangle)
+}
+
+/// Shrinks `img` to fit within `cell`x`cell` pixels, for `contact_sheet`.
+pub fn contact_sheet_cell(img: &DynamicImage, cell: u32) -> RgbImage {
+    match img.width() > cell || img.height() > cell {
+        true => img.resize(cell, cell, FilterType::Triangle).into_rgb8(),
+        false => img.to_rgb8(),
+    }
+}
+
+/// Tiles equally sized `cells`, from `contact_sheet_cell`, into a grid as
+/// close to square as it gets, row by row.
+pub fn contact_sheet(cells: &[RgbImage]) -> DynamicImage {
+    let columns = (cells.len() as f64).sqrt().ceil() as u32;
+    let rows = (cells.len() as u32).div_ceil(columns);
+    let (cell_w, cell_h) = cells[0].dimensions();
+
+    let mut sheet = RgbImage::new(columns * cell_w, rows * cell_h);
+    for (i, img) in cells.iter().enumerate() {
+        let (column, row) = (i as u32 % columns, i as u32 / columns);
+        image::imageops::overlay(
+            &mut sheet,
+            img,
+            (column * cell_w) as i64,
+            (row * cell_h) as i64,
+        );
+    }
+    DynamicImage::ImageRgb8(sheet)
+}
+
+fn parse_size(size: &str) -> Result<(u32, u32), TransformError> {
+    let invalid = || TransformError::InvalidSize(size.to_string());
+    let (w, h) = size.split_once('x').ok_or_else(invalid)?;
+    let w: u32 = w.parse().map_err(|_| invalid())?;
+    let h: u32 = h.parse().map_err(|_| invalid())?;
+    if !(1..=MAX_RESIZE_DIMENSION).contains(&w) || !(1..=MAX_RESIZE_DIMENSION).contains(&h) {
+        return Err(invalid());
+    }
+    Ok((w, h))
+}
+
+pub(crate) fn parse_color(color: &str) -> Result<Rgb<u8>, TransformError> {
+    let invalid = || TransformError::InvalidColor(color.to_string());
+    let hex = color.trim_start_matches('#');
+    if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+    Ok(Rgb([channel(0)?, channel(2)?, channel(4)?]))
+}
@@ -0,0 +1,102 @@
+use image::{imageops::FilterType, DynamicImage, GrayImage, Luma, Rgb, RgbImage};
+
+/// How hard one YCbCr component is crushed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ComponentCrush {
+    /// The component is shrunk by this factor, in `(0, 1]`, before being
+    /// recompressed, then stretched back.
+    pub scale: f32,
+    /// JPEG quality the shrunk component is recompressed at.
+    pub quality: u8,
+}
+
+/// Separate crush settings for luma (Y) and chroma (Cb and Cr).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct YCbCrCrush {
+    pub luma: ComponentCrush,
+    pub chroma: ComponentCrush,
+}
+
+impl Default for YCbCrCrush {
+    /// Sharp edges, smeared colors.
+    fn default() -> Self {
+        Self {
+            luma: ComponentCrush {
+                scale: 1.0,
+                quality: 40,
+            },
+            chroma: ComponentCrush {
+                scale: 0.25,
+                quality: 10,
+            },
+        }
+    }
+}
+
+/// Splits `img` into its Y, Cb and Cr planes (full-range, as in JFIF),
+/// crushes each on its own and puts them back together.
+pub fn crush(img: &DynamicImage, settings: YCbCrCrush) -> Result<DynamicImage, image::ImageError> {
+    let rgb = img.to_rgb8();
+    let (w, h) = rgb.dimensions();
+    let mut planes = [
+        GrayImage::new(w, h),
+        GrayImage::new(w, h),
+        GrayImage::new(w, h),
+    ];
+    for (x, y, pixel) in rgb.enumerate_pixels() {
+        for (plane, value) in planes.iter_mut().zip(to_ycbcr(*pixel)) {
+            plane.put_pixel(x, y, Luma([value]));
+        }
+    }
+
+    let [luma, cb, cr] = planes;
+    let luma = crush_plane(luma, settings.luma)?;
+    let cb = crush_plane(cb, settings.chroma)?;
+    let cr = crush_plane(cr, settings.chroma)?;
+
+    let out = RgbImage::from_fn(w, h, |x, y| {
+        to_rgb([
+            luma.get_pixel(x, y)[0],
+            cb.get_pixel(x, y)[0],
+            cr.get_pixel(x, y)[0],
+        ])
+    });
+    Ok(DynamicImage::ImageRgb8(out))
+}
+
+fn crush_plane(plane: GrayImage, settings: ComponentCrush) -> Result<GrayImage, image::ImageError> {
+    let (w, h) = plane.dimensions();
+    let small_w = ((w as f32 * settings.scale) as u32).max(1);
+    let small_h = ((h as f32 * settings.scale) as u32).max(1);
+    let small =
+        DynamicImage::ImageLuma8(plane).resize_exact(small_w, small_h, FilterType::Triangle);
+
+    let mut out: Vec<u8> = Default::default();
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut out, settings.quality)
+        .encode_image(&small)?;
+    let decoded = image::load_from_memory_with_format(&out[..], image::ImageFormat::Jpeg)?;
+    // Stretching back smoothly is what smears the colors.
+    Ok(decoded
+        .resize_exact(w, h, FilterType::Triangle)
+        .into_luma8())
+}
+
+fn to_ycbcr(Rgb([r, g, b]): Rgb<u8>) -> [u8; 3] {
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+    [
+        0.299 * r + 0.587 * g + 0.114 * b,
+        128.0 - 0.168_736 * r - 0.331_264 * g + 0.5 * b,
+        128.0 + 0.5 * r - 0.418_688 * g - 0.081_312 * b,
+    ]
+    .map(|v| v.round().clamp(0.0, 255.0) as u8)
+}
+
+fn to_rgb([y, cb, cr]: [u8; 3]) -> Rgb<u8> {
+    let (y, cb, cr) = (y as f32, cb as f32 - 128.0, cr as f32 - 128.0);
+    Rgb([
+        y + 1.402 * cr,
+        y - 0.344_136 * cb - 0.714_136 * cr,
+        y + 1.772 * cb,
+    ]
+    .map(|v| v.round().clamp(0.0, 255.0) as u8))
+}